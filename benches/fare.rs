@@ -1,7 +1,10 @@
 extern crate fare_estimation;
 
+use chrono::prelude::*;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use fare_estimation::fare_estimation::estimate_fare;
+use fare_estimation::fare_estimation::{
+    estimate_fare, Distance, Location, Options, Position, Ride, Tariff,
+};
 use std::io;
 
 pub fn bench_calculate_fares_small_file(c: &mut Criterion) {
@@ -11,7 +14,9 @@ pub fn bench_calculate_fares_small_file(c: &mut Criterion) {
         b.iter(|| {
             let input = std::fs::File::open("paths.csv").unwrap();
             tokio_rt
-                .block_on(async { estimate_fare(black_box(input), io::sink()).await })
+                .block_on(async {
+                    estimate_fare(black_box(input), io::sink(), Options::default()).await
+                })
                 .unwrap();
         })
     });
@@ -24,7 +29,9 @@ pub fn bench_calculate_fares_medium_file(c: &mut Criterion) {
         b.iter(|| {
             let input = std::fs::File::open("pathsbig.csv").unwrap();
             tokio_rt
-                .block_on(async { estimate_fare(black_box(input), io::sink()).await })
+                .block_on(async {
+                    estimate_fare(black_box(input), io::sink(), Options::default()).await
+                })
                 .unwrap();
         })
     });
@@ -37,16 +44,160 @@ pub fn bench_calculate_fares_large_file(c: &mut Criterion) {
         b.iter(|| {
             let input = std::fs::File::open("large.csv").unwrap();
             tokio_rt
-                .block_on(async { estimate_fare(black_box(input), io::sink()).await })
+                .block_on(async {
+                    estimate_fare(black_box(input), io::sink(), Options::default()).await
+                })
                 .unwrap();
         })
     });
 }
 
+// The large file end to end, measuring close fixes on a flat Earth or all of
+// them with haversine.
+pub fn bench_flat_earth_large_file(c: &mut Criterion) {
+    let tokio_rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("calc_fares_large_file_distance");
+    group.sample_size(10);
+
+    for flat_earth in [true, false] {
+        let name = if flat_earth {
+            "flat_earth"
+        } else {
+            "haversine"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let input = std::fs::File::open("large.csv").unwrap();
+                let options = Options {
+                    tariff: Tariff {
+                        flat_earth,
+                        ..Tariff::default()
+                    },
+                    ..Options::default()
+                };
+                tokio_rt
+                    .block_on(async { estimate_fare(black_box(input), io::sink(), options).await })
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// Consecutive fixes of the small file, which are mostly a few metres apart.
+fn small_file_legs() -> Vec<(Location, Location)> {
+    let rows = std::fs::read_to_string("paths.csv").unwrap();
+    let locations: Vec<Location> = rows
+        .lines()
+        .map(|row| {
+            let fields: Vec<&str> = row.split(',').collect();
+            Location {
+                latitude: fields[1].parse().unwrap(),
+                longitude: fields[2].parse().unwrap(),
+            }
+        })
+        .collect();
+    locations
+        .windows(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect()
+}
+
+pub fn bench_distance_haversine(c: &mut Criterion) {
+    let legs = small_file_legs();
+
+    c.bench_function("distance_haversine", |b| {
+        b.iter(|| {
+            legs.iter()
+                .map(|(start, end)| Distance::Haversine.km(black_box(start), black_box(end)))
+                .sum::<f64>()
+        })
+    });
+}
+
+// The rides of the small file, without reading or writing them.
+fn small_file_rides() -> Vec<Ride> {
+    let mut rides: Vec<Ride> = vec![];
+    let mut positions = vec![];
+    let rows = std::fs::read_to_string("paths.csv").unwrap();
+    let mut rows = rows
+        .lines()
+        .map(|row| row.split(',').collect::<Vec<_>>())
+        .peekable();
+    while let Some(fields) = rows.next() {
+        positions.push(
+            Position::new(
+                Utc.timestamp(fields[3].parse().unwrap(), 0),
+                Location {
+                    latitude: fields[1].parse().unwrap(),
+                    longitude: fields[2].parse().unwrap(),
+                },
+                None,
+            )
+            .unwrap(),
+        );
+        if rows.peek().map(|next| next[0]) != Some(fields[0]) {
+            rides.push(Ride::new(
+                fields[0].parse().unwrap(),
+                positions.split_off(0),
+            ));
+        }
+    }
+    rides
+}
+
+// Pricing the small file's rides, measuring close fixes on a flat Earth or
+// all of them with haversine.
+pub fn bench_distance_flat_earth(c: &mut Criterion) {
+    let rides = small_file_rides();
+    let mut group = c.benchmark_group("price_rides_distance");
+
+    for flat_earth in [true, false] {
+        let name = if flat_earth {
+            "flat_earth"
+        } else {
+            "haversine"
+        };
+        let tariff = Tariff {
+            flat_earth,
+            ..Tariff::default()
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                rides
+                    .iter()
+                    .map(|ride| {
+                        ride.calculate_fare(black_box(&tariff))
+                            .amount()
+                            .minor_units()
+                    })
+                    .sum::<i64>()
+            })
+        });
+    }
+    group.finish();
+}
+
+pub fn bench_distance_wgs84(c: &mut Criterion) {
+    let legs = small_file_legs();
+
+    c.bench_function("distance_wgs84", |b| {
+        b.iter(|| {
+            legs.iter()
+                .map(|(start, end)| Distance::Wgs84.km(black_box(start), black_box(end)))
+                .sum::<f64>()
+        })
+    });
+}
+
 criterion_group!(
     benches,
+    bench_distance_haversine,
+    bench_distance_flat_earth,
+    bench_distance_wgs84,
     bench_calculate_fares_small_file,
     bench_calculate_fares_medium_file,
     bench_calculate_fares_large_file,
+    bench_flat_earth_large_file,
 );
 criterion_main!(benches);
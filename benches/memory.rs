@@ -0,0 +1,101 @@
+extern crate fare_estimation;
+
+use fare_estimation::fare_estimation::{estimate_fare, Options};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::time::Instant;
+
+const RIDES: u32 = 100_000;
+const POSITIONS_PER_RIDE: u32 = 100;
+
+// Generates rides as CSV on the fly, so the input never sits in memory and
+// the peak RSS only reflects what the pipeline buffers.
+struct GeneratedInput {
+    ride: u32,
+    position: u32,
+    line: Vec<u8>,
+    offset: usize,
+}
+
+impl GeneratedInput {
+    fn new() -> Self {
+        GeneratedInput {
+            ride: 0,
+            position: 0,
+            line: vec![],
+            offset: 0,
+        }
+    }
+
+    fn next_line(&mut self) -> bool {
+        if self.ride == RIDES {
+            return false;
+        }
+
+        self.line.clear();
+        self.offset = 0;
+        writeln!(
+            self.line,
+            "{},{:.6},{:.6},{}",
+            self.ride,
+            37.966660 + self.position as f64 * 0.0001,
+            23.728308,
+            1405594957 + self.position * 10,
+        )
+        .unwrap();
+
+        self.position += 1;
+        if self.position == POSITIONS_PER_RIDE {
+            self.position = 0;
+            self.ride += 1;
+        }
+        true
+    }
+}
+
+impl io::Read for GeneratedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.line.len() && !self.next_line() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.line.len() - self.offset);
+        buf[..len].copy_from_slice(&self.line[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+// High-water mark of the resident set size, in kB (Linux only).
+fn peak_rss_kb() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn main() {
+    let tokio_rt = tokio::runtime::Runtime::new().unwrap();
+    let start = Instant::now();
+
+    let summary = tokio_rt
+        .block_on(estimate_fare(
+            GeneratedInput::new(),
+            io::sink(),
+            Options::default(),
+        ))
+        .unwrap();
+
+    println!(
+        "{} rides of {} positions in {:.2?}, peak RSS: {} kB",
+        summary.rides,
+        POSITIONS_PER_RIDE,
+        start.elapsed(),
+        peak_rss_kb().map_or("unknown".to_string(), |kb| kb.to_string()),
+    );
+}
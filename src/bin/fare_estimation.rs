@@ -1,11 +1,741 @@
 extern crate fare_estimation;
 
-use fare_estimation::fare_estimation::{estimate_fare, MainError};
+use fare_estimation::fare_estimation::{
+    estimate_fare, serve, AmountFormat, AuditSink, CsvAuditSink, CsvColumns, CsvErrorSink,
+    CsvInput, ErrorSink, InputFormat, JsonLinesAuditSink, MainError, Options, OutputFormat,
+    ReadError, Rejection, RideGrouping, ServerOptions, Summary, Tariff, TariffError,
+    TimestampConflict, TimestampFormat,
+};
 use std::fs::File;
+use std::io;
+use std::net::TcpListener;
+use std::num::NonZeroUsize;
+use std::process;
+use std::str::FromStr;
+
+const USAGE: &str = "Usage: fare_estimation [OPTIONS] [INPUT] [OUTPUT]
+       fare_estimation serve [SERVE OPTIONS]
+
+Estimates the fare of every ride in INPUT and writes them to OUTPUT.
+Use `-` for stdin or stdout.
+
+Arguments:
+  [INPUT]   Positions file [default: paths.csv]
+  [OUTPUT]  Fares file [default: out.csv]
+
+Options:
+  -i, --input <PATH>     Positions file, same as INPUT
+  -f, --input-format <FORMAT>
+                         Positions as `csv` or `jsonl` (JSON Lines) [default: csv]
+  -d, --delimiter <CHAR> CSV field delimiter [default: ,]
+      --timestamps <FORMAT>
+                         Timestamps as `seconds`, `milliseconds`, `microseconds`,
+                         `rfc3339`, or `auto` to tell from each value [default: auto]
+      --header           CSV input starts with a header row naming the columns
+                         id_ride, lat, lng and timestamp, in any order
+      --columns <ID,LAT,LNG,TIMESTAMP[,ACCURACY]>
+                         CSV input starts with a header row; take the ride id,
+                         latitude, longitude, timestamp and optionally the
+                         accuracy in metres from these columns
+      --grouping <MODE>  Take rides as runs of `consecutive` rows with the same id,
+                         the same but `reject-reappearing` rows of a finished ride,
+                         or all rows `by-id`, writing fares in order of id
+                         [default: consecutive]
+      --max-positions-in-memory <N>
+                         With `--grouping by-id`, positions held before sorting
+                         them out to temporary files [default: 1000000]
+      --normalize <CONFLICTS>
+                         Order each ride's positions by timestamp and drop exact
+                         duplicates, resolving fixes that share a timestamp by
+                         `keep-first`, `keep-last`, `average` or `drop-all`
+  -o, --output <PATH>    Fares file, same as OUTPUT
+  -F, --output-format <FORMAT>
+                         Fares as `csv`, `jsonl` (JSON Lines) or `json` (one array)
+                         [default: csv]
+      --amounts <FORMAT> Amounts as `decimal` (12.30) or `minor-units` (1230)
+                         [default: decimal]
+  -t, --tariff <PATH>    Tariff file (.toml or .json) [default: built-in tariff]
+  -r, --rejected <PATH>  Write rejected rows as CSV to PATH [default: stderr]
+  -u, --unordered        Write fares as soon as they are ready, not in input order
+  -b, --breakdown        Write how each fare was made up, with a header row
+  -a, --audit <PATH>     Write every segment considered to PATH, as JSON Lines if
+                         it ends in .jsonl, as CSV otherwise
+      --capacity <N>     Rides read ahead of the fare calculations [default: 1024]
+      --max-in-flight <N>
+                         Rides priced or waiting to be written at once [default: 256]
+  -h, --help             Print this help
+
+Serve options:
+      --address <ADDR>   Listen for HTTP requests on ADDR [default: 127.0.0.1:8080]
+  -t, --tariff <PATH>    Tariff file (.toml or .json) [default: built-in tariff]
+      --timestamps <FORMAT>
+                         Timestamps as with estimating [default: auto]
+      --amounts <FORMAT> Amounts as with estimating [default: decimal]
+
+  POST /fare takes one ride as JSON, e.g.
+  {\"id_ride\":1,\"positions\":[{\"lat\":37.96,\"lng\":23.72,\"timestamp\":1405594957}]},
+  and answers with its fare and breakdown. POST /fares takes positions as CSV
+  and streams back the fares as CSV.
+
+Exit codes:
+  0  Success
+  1  Partial success: some rows were rejected
+  2  Invalid command line
+  3  I/O failure
+  4  Parse failure";
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_PARTIAL_SUCCESS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO_FAILURE: i32 = 3;
+const EXIT_PARSE_FAILURE: i32 = 4;
+
+const STDIO: &str = "-";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    input: String,
+    output: String,
+    input_format: InputFormat,
+    timestamp_format: TimestampFormat,
+    grouping: RideGrouping,
+    normalize: Option<TimestampConflict>,
+    output_format: OutputFormat,
+    amount_format: AmountFormat,
+    tariff: Option<String>,
+    rejected: Option<String>,
+    audit: Option<String>,
+    unordered: bool,
+    breakdown: bool,
+    capacity: Option<NonZeroUsize>,
+    max_in_flight: Option<NonZeroUsize>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            input: "paths.csv".to_string(),
+            output: "out.csv".to_string(),
+            input_format: InputFormat::default(),
+            timestamp_format: TimestampFormat::Auto,
+            grouping: RideGrouping::Consecutive,
+            normalize: None,
+            output_format: OutputFormat::Csv,
+            amount_format: AmountFormat::Decimal,
+            tariff: None,
+            rejected: None,
+            audit: None,
+            unordered: false,
+            breakdown: false,
+            capacity: None,
+            max_in_flight: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ServeArgs {
+    address: String,
+    tariff: Option<String>,
+    timestamp_format: TimestampFormat,
+    amount_format: AmountFormat,
+}
+
+impl Default for ServeArgs {
+    fn default() -> Self {
+        ServeArgs {
+            address: "127.0.0.1:8080".to_string(),
+            tariff: None,
+            timestamp_format: TimestampFormat::Auto,
+            amount_format: AmountFormat::Decimal,
+        }
+    }
+}
+
+// Only ever built once, so the size of `Args` doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Estimate(Args),
+    Serve(ServeArgs),
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.peekable();
+    if args.peek().map(String::as_str) == Some("serve") {
+        args.next();
+        return parse_serve_args(args);
+    }
+    parse_estimate_args(args)
+}
+
+fn parse_serve_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut parsed = ServeArgs::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--address" => parsed.address = value(&arg)?,
+            "-t" | "--tariff" => parsed.tariff = Some(value(&arg)?),
+            "--timestamps" => parsed.timestamp_format = parse_value(&arg, value(&arg)?)?,
+            "--amounts" => parsed.amount_format = parse_value(&arg, value(&arg)?)?,
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    Ok(Command::Serve(parsed))
+}
+
+fn parse_estimate_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut parsed = Args::default();
+    let mut input = None;
+    let mut output = None;
+    let mut positionals = vec![];
+    let mut csv = CsvInput::default();
+    let mut csv_flag = None;
+    let mut max_positions_in_memory = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-i" | "--input" => input = Some(value(&arg)?),
+            "-f" | "--input-format" => parsed.input_format = parse_value(&arg, value(&arg)?)?,
+            "-d" | "--delimiter" => {
+                csv.delimiter = parse_delimiter(&arg, value(&arg)?)?;
+                csv_flag = Some(arg);
+            }
+            "--header" => {
+                csv.columns = Some(CsvColumns::default());
+                csv_flag = Some(arg);
+            }
+            "--columns" => {
+                csv.columns = Some(parse_columns(&arg, value(&arg)?)?);
+                csv_flag = Some(arg);
+            }
+            "--timestamps" => parsed.timestamp_format = parse_value(&arg, value(&arg)?)?,
+            "--grouping" => parsed.grouping = parse_value(&arg, value(&arg)?)?,
+            "--normalize" => parsed.normalize = Some(parse_value(&arg, value(&arg)?)?),
+            "--max-positions-in-memory" => {
+                max_positions_in_memory = Some(parse_value(&arg, value(&arg)?)?)
+            }
+            "-F" | "--output-format" => parsed.output_format = parse_value(&arg, value(&arg)?)?,
+            "--amounts" => parsed.amount_format = parse_value(&arg, value(&arg)?)?,
+            "-o" | "--output" => output = Some(value(&arg)?),
+            "-t" | "--tariff" => parsed.tariff = Some(value(&arg)?),
+            "-r" | "--rejected" => parsed.rejected = Some(value(&arg)?),
+            "-a" | "--audit" => parsed.audit = Some(value(&arg)?),
+            "-u" | "--unordered" => parsed.unordered = true,
+            "-b" | "--breakdown" => parsed.breakdown = true,
+            "--capacity" => parsed.capacity = Some(parse_value(&arg, value(&arg)?)?),
+            "--max-in-flight" => parsed.max_in_flight = Some(parse_value(&arg, value(&arg)?)?),
+            flag if flag.starts_with('-') && flag != STDIO => {
+                return Err(format!("unknown option {}", flag))
+            }
+            _ => positionals.push(arg),
+        }
+    }
+
+    // Positional paths fill whichever of input and output weren't flagged.
+    let mut positionals = positionals.into_iter();
+    for (flag, slot) in [(input, &mut parsed.input), (output, &mut parsed.output)] {
+        if let Some(path) = flag.or_else(|| positionals.next()) {
+            *slot = path;
+        }
+    }
+    if let Some(extra) = positionals.next() {
+        return Err(format!("unexpected argument {}", extra));
+    }
+
+    match (&mut parsed.input_format, csv_flag) {
+        (InputFormat::Csv(format), _) => *format = csv,
+        (_, Some(flag)) => return Err(format!("{} only applies to CSV input", flag)),
+        (_, None) => {}
+    }
+
+    match (&mut parsed.grouping, max_positions_in_memory) {
+        (
+            RideGrouping::ById {
+                max_positions_in_memory: slot,
+            },
+            Some(limit),
+        ) => *slot = limit,
+        (_, Some(_)) => {
+            return Err("--max-positions-in-memory only applies to --grouping by-id".to_string())
+        }
+        (_, None) => {}
+    }
+
+    Ok(Command::Estimate(parsed))
+}
+
+fn parse_value<T: FromStr>(name: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+fn parse_delimiter(name: &str, value: String) -> Result<u8, String> {
+    match value.as_bytes() {
+        &[delimiter] => Ok(delimiter),
+        _ => Err(format!("invalid value for {}: {}", name, value)),
+    }
+}
+
+fn parse_columns(name: &str, value: String) -> Result<CsvColumns, String> {
+    match value.split(',').collect::<Vec<_>>().as_slice() {
+        &[id, latitude, longitude, timestamp, ref accuracy @ ..] if accuracy.len() <= 1 => {
+            Ok(CsvColumns {
+                id: id.to_string(),
+                latitude: latitude.to_string(),
+                longitude: longitude.to_string(),
+                timestamp: timestamp.to_string(),
+                accuracy: accuracy.first().map(|name| name.to_string()),
+            })
+        }
+        _ => Err(format!("invalid value for {}: {}", name, value)),
+    }
+}
+
+fn exit_code(result: &Result<Summary, MainError>) -> i32 {
+    match result {
+        Ok(summary) if summary.rejected_rows > 0 => EXIT_PARTIAL_SUCCESS,
+        Ok(_) => EXIT_SUCCESS,
+        Err(err) => failure_code(err),
+    }
+}
+
+fn failure_code(err: &MainError) -> i32 {
+    match err {
+        MainError::IOError(_) | MainError::TariffError(TariffError::IOError(_)) => EXIT_IO_FAILURE,
+        MainError::ReadError(ReadError::CSVError(err)) if err.is_io_error() => EXIT_IO_FAILURE,
+        MainError::ReadError(ReadError::IOError(_)) | MainError::HTTPError(_) => EXIT_IO_FAILURE,
+        MainError::TariffError(_) | MainError::ReadError(_) => EXIT_PARSE_FAILURE,
+        MainError::OptionError(..) => EXIT_USAGE,
+    }
+}
+
+fn open_input(path: &str) -> io::Result<Box<dyn io::Read + Send>> {
+    if path == STDIO {
+        return Ok(Box::new(io::stdin()));
+    }
+    Ok(Box::new(File::open(path)?))
+}
+
+fn create_output(path: &str) -> io::Result<Box<dyn io::Write + Send>> {
+    if path == STDIO {
+        return Ok(Box::new(io::stdout()));
+    }
+    Ok(Box::new(File::create(path)?))
+}
+
+fn report_rejection(rejection: Rejection) {
+    match rejection.line {
+        Some(line) => eprintln!("rejected line {}: {}", line, rejection.reason),
+        None => eprintln!("rejected: {}", rejection.reason),
+    }
+}
+
+fn load_tariff(path: &Option<String>) -> Result<Tariff, TariffError> {
+    match path {
+        Some(path) => Tariff::from_file(path),
+        None => Ok(Tariff::default()),
+    }
+}
+
+async fn run_server(args: ServeArgs) -> Result<(), MainError> {
+    let options = ServerOptions {
+        tariff: load_tariff(&args.tariff)?,
+        timestamp_format: args.timestamp_format,
+        amount_format: args.amount_format,
+    };
+    let listener = TcpListener::bind(&args.address)?;
+    eprintln!("listening on http://{}", listener.local_addr()?);
+
+    serve(listener, options).await
+}
+
+// Outputs are only created once the tariff and input are known to be good,
+// so that a mistyped path doesn't wipe out the last run's results.
+async fn run(args: Args) -> Result<Summary, MainError> {
+    let tariff = load_tariff(&args.tariff)?;
+    let input = open_input(&args.input)?;
+    let errors: Box<dyn ErrorSink> = match &args.rejected {
+        Some(path) => Box::new(CsvErrorSink::new(create_output(path)?)),
+        None => Box::new(report_rejection),
+    };
+    let audit: Option<Box<dyn AuditSink>> = match &args.audit {
+        Some(path) if path.ends_with(".jsonl") => {
+            Some(Box::new(JsonLinesAuditSink::new(create_output(path)?)))
+        }
+        Some(path) => Some(Box::new(CsvAuditSink::new(create_output(path)?))),
+        None => None,
+    };
+    let output = create_output(&args.output)?;
+
+    let mut options = Options {
+        tariff,
+        input_format: args.input_format,
+        timestamp_format: args.timestamp_format,
+        grouping: args.grouping,
+        normalize: args.normalize,
+        output_format: args.output_format,
+        amount_format: args.amount_format,
+        errors,
+        preserve_order: !args.unordered,
+        breakdown: args.breakdown,
+        audit,
+        ..Options::default()
+    };
+    if let Some(capacity) = args.capacity {
+        options.channel_capacity = capacity.get();
+    }
+    if let Some(max_in_flight) = args.max_in_flight {
+        options.max_in_flight = max_in_flight.get();
+    }
+
+    estimate_fare(input, output, options).await
+}
 
 #[tokio::main]
-pub async fn main() -> Result<(), MainError> {
-    let input = File::open("paths.csv")?;
-    let output = File::create("out.csv")?;
-    estimate_fare(input, output).await
+pub async fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Estimate(args)) => args,
+        Ok(Command::Serve(args)) => {
+            if let Err(err) = run_server(args).await {
+                eprintln!("error: {:?}", err);
+                process::exit(failure_code(&err));
+            }
+            return;
+        }
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let result = run(args).await;
+    match &result {
+        Ok(summary) if summary.rejected_rows > 0 => eprintln!(
+            "warning: {} rows rejected, {} rides estimated",
+            summary.rejected_rows, summary.rides
+        ),
+        Ok(_) => {}
+        Err(err) => eprintln!("error: {:?}", err),
+    }
+    if let Ok(summary) = &result {
+        let normalized = summary.normalized;
+        if !normalized.is_empty() {
+            eprintln!(
+                "normalized: {} positions reordered, {} duplicates dropped, \
+                 {} timestamp conflicts resolved",
+                normalized.reordered, normalized.duplicates, normalized.conflicts
+            );
+        }
+    }
+
+    process::exit(exit_code(&result));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn it_uses_defaults() {
+        assert_eq!(Ok(Command::Estimate(Args::default())), parse(&[]));
+    }
+
+    #[test]
+    fn it_parses_positional_paths() {
+        assert_eq!(
+            Ok(Command::Estimate(Args {
+                input: "-".to_string(),
+                output: "fares.csv".to_string(),
+                input_format: InputFormat::default(),
+                timestamp_format: TimestampFormat::Auto,
+                grouping: RideGrouping::Consecutive,
+                normalize: None,
+                output_format: OutputFormat::Csv,
+                amount_format: AmountFormat::Decimal,
+                tariff: None,
+                rejected: None,
+                audit: None,
+                unordered: false,
+                breakdown: false,
+                capacity: None,
+                max_in_flight: None,
+            })),
+            parse(&["-", "fares.csv"])
+        );
+    }
+
+    #[test]
+    fn it_parses_flags() {
+        assert_eq!(
+            Ok(Command::Estimate(Args {
+                input: "in.csv".to_string(),
+                output: "-".to_string(),
+                input_format: InputFormat::JsonLines,
+                timestamp_format: TimestampFormat::Milliseconds,
+                grouping: RideGrouping::RejectReappearing,
+                normalize: Some(TimestampConflict::Average),
+                output_format: OutputFormat::JsonArray,
+                amount_format: AmountFormat::MinorUnits,
+                tariff: Some("athens.toml".to_string()),
+                rejected: Some("rejected.csv".to_string()),
+                audit: Some("segments.jsonl".to_string()),
+                unordered: true,
+                breakdown: true,
+                capacity: NonZeroUsize::new(10),
+                max_in_flight: NonZeroUsize::new(4),
+            })),
+            parse(&[
+                "--tariff",
+                "athens.toml",
+                "-o",
+                "-",
+                "-i",
+                "in.csv",
+                "--input-format",
+                "jsonl",
+                "--timestamps",
+                "milliseconds",
+                "--grouping",
+                "reject-reappearing",
+                "--normalize",
+                "average",
+                "-F",
+                "json",
+                "--amounts",
+                "minor-units",
+                "-r",
+                "rejected.csv",
+                "--unordered",
+                "-b",
+                "--audit",
+                "segments.jsonl",
+                "--capacity",
+                "10",
+                "--max-in-flight",
+                "4",
+            ])
+        );
+        assert_eq!(
+            Ok(Command::Estimate(Args {
+                output: "-".to_string(),
+                ..Args::default()
+            })),
+            parse(&["paths.csv", "--output", "-"])
+        );
+        assert_eq!(
+            Ok(Command::Estimate(Args {
+                input: "in.csv".to_string(),
+                output: "fares.csv".to_string(),
+                input_format: InputFormat::default(),
+                timestamp_format: TimestampFormat::Auto,
+                grouping: RideGrouping::Consecutive,
+                normalize: None,
+                output_format: OutputFormat::Csv,
+                amount_format: AmountFormat::Decimal,
+                tariff: None,
+                rejected: None,
+                audit: None,
+                unordered: false,
+                breakdown: false,
+                capacity: None,
+                max_in_flight: None,
+            })),
+            parse(&["-i", "in.csv", "fares.csv"])
+        );
+        assert_eq!(Ok(Command::Help), parse(&["in.csv", "--help"]));
+    }
+
+    #[test]
+    fn it_parses_csv_input_flags() {
+        let csv = |args: &[&str]| match parse(args) {
+            Ok(Command::Estimate(Args {
+                input_format: InputFormat::Csv(csv),
+                ..
+            })) => csv,
+            other => panic!("expected CSV input, got {:?}", other),
+        };
+
+        assert_eq!(
+            CsvInput {
+                delimiter: b';',
+                columns: Some(CsvColumns::default()),
+            },
+            csv(&["-d", ";", "--header"])
+        );
+        assert_eq!(
+            CsvInput {
+                delimiter: b'\t',
+                columns: Some(CsvColumns {
+                    id: "ride".to_string(),
+                    latitude: "latitude".to_string(),
+                    longitude: "longitude".to_string(),
+                    timestamp: "time".to_string(),
+                    accuracy: Some("hdop_m".to_string()),
+                }),
+            },
+            csv(&[
+                "--columns",
+                "ride,latitude,longitude,time,hdop_m",
+                "--delimiter",
+                "\t",
+                "-f",
+                "csv"
+            ])
+        );
+        assert!(parse(&["-f", "jsonl", "--header"]).is_err());
+        assert!(parse(&["--delimiter", ";;"]).is_err());
+        assert!(parse(&["--columns", "ride,lat,lng"]).is_err());
+        assert!(parse(&["--columns", "ride,lat,lng,time,accuracy,speed"]).is_err());
+    }
+
+    #[test]
+    fn it_parses_grouping_flags() {
+        let grouping = |args: &[&str]| match parse(args) {
+            Ok(Command::Estimate(args)) => args.grouping,
+            other => panic!("expected to estimate, got {:?}", other),
+        };
+
+        assert_eq!(
+            RideGrouping::ById {
+                max_positions_in_memory: 1_000_000
+            },
+            grouping(&["--grouping", "by-id"])
+        );
+        assert_eq!(
+            RideGrouping::ById {
+                max_positions_in_memory: 500
+            },
+            grouping(&["--max-positions-in-memory", "500", "--grouping", "by-id"])
+        );
+        assert!(parse(&["--grouping", "by-time"]).is_err());
+        assert!(parse(&["--max-positions-in-memory", "500"]).is_err());
+        assert!(parse(&["--grouping", "by-id", "--max-positions-in-memory", "x"]).is_err());
+    }
+
+    #[test]
+    fn it_parses_serve_flags() {
+        assert_eq!(Ok(Command::Serve(ServeArgs::default())), parse(&["serve"]));
+        assert_eq!(
+            Ok(Command::Serve(ServeArgs {
+                address: "0.0.0.0:9000".to_string(),
+                tariff: Some("athens.toml".to_string()),
+                timestamp_format: TimestampFormat::Milliseconds,
+                amount_format: AmountFormat::MinorUnits,
+            })),
+            parse(&[
+                "serve",
+                "--address",
+                "0.0.0.0:9000",
+                "-t",
+                "athens.toml",
+                "--timestamps",
+                "milliseconds",
+                "--amounts",
+                "minor-units",
+            ])
+        );
+        assert_eq!(Ok(Command::Help), parse(&["serve", "--help"]));
+        assert!(parse(&["serve", "--unordered"]).is_err());
+        assert!(parse(&["serve", "paths.csv"]).is_err());
+        // Only as the first argument.
+        assert!(matches!(
+            parse(&["paths.csv", "serve"]),
+            Ok(Command::Estimate(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_invalid_arguments() {
+        assert!(parse(&["--speed", "10"]).is_err());
+        assert!(parse(&["--tariff"]).is_err());
+        assert!(parse(&["--capacity", "-1"]).is_err());
+        assert!(parse(&["--capacity", "0"]).is_err());
+        assert!(parse(&["--max-in-flight", "0"]).is_err());
+        assert!(parse(&["--input-format", "xml"]).is_err());
+        assert!(parse(&["--output-format", "xml"]).is_err());
+        assert!(parse(&["--amounts", "cents"]).is_err());
+        assert!(parse(&["--timestamps", "ns"]).is_err());
+        assert!(parse(&["--normalize", "sort"]).is_err());
+        assert!(parse(&["-i", "a.csv", "b.csv", "c.csv"]).is_err());
+        assert!(parse(&["a.csv", "b.csv", "c.csv"]).is_err());
+    }
+
+    #[test]
+    fn it_maps_results_to_exit_codes() {
+        let ok = Summary {
+            rides: 3,
+            rejected_rows: 0,
+            ..Summary::default()
+        };
+        let partial = Summary {
+            rides: 3,
+            rejected_rows: 1,
+            ..Summary::default()
+        };
+        let io_error = io::Error::new(io::ErrorKind::NotFound, "paths.csv");
+
+        assert_eq!(EXIT_SUCCESS, exit_code(&Ok(ok)));
+        assert_eq!(EXIT_PARTIAL_SUCCESS, exit_code(&Ok(partial)));
+        assert_eq!(EXIT_IO_FAILURE, exit_code(&Err(io_error.into())));
+        assert_eq!(
+            EXIT_IO_FAILURE,
+            exit_code(&Err(Tariff::from_file("missing.toml").unwrap_err().into()))
+        );
+        assert_eq!(
+            EXIT_PARSE_FAILURE,
+            exit_code(&Err(Tariff::from_toml_str("max_speed = -1.0")
+                .unwrap_err()
+                .into()))
+        );
+    }
+
+    #[test]
+    fn it_keeps_outputs_when_the_input_is_missing() {
+        let directory = std::env::temp_dir().join(format!("fare_estimation_{}", process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_str().unwrap().to_string();
+        for name in ["out.csv", "rejected.csv", "audit.csv"] {
+            std::fs::write(path(name), "last run\n").unwrap();
+        }
+
+        let args = Args {
+            input: path("missing.csv"),
+            output: path("out.csv"),
+            rejected: Some(path("rejected.csv")),
+            audit: Some(path("audit.csv")),
+            ..Args::default()
+        };
+        let result = tokio::runtime::Runtime::new().unwrap().block_on(run(args));
+
+        assert_eq!(EXIT_IO_FAILURE, exit_code(&result));
+        for name in ["out.csv", "rejected.csv", "audit.csv"] {
+            assert_eq!("last run\n", std::fs::read_to_string(path(name)).unwrap());
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -0,0 +1,152 @@
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+
+use super::money::Amount;
+
+// How a segment was priced, or which filter discarded it. A segment that
+// crosses into or out of the night is classified by the rate at its start,
+// though its amount covers both.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Classification {
+    Idle,
+    Day,
+    Night,
+    DiscardedTooFast,
+    DiscardedAcceleration,
+    DiscardedTooShort,
+    DiscardedInaccurate,
+    DiscardedSpike,
+}
+
+// One pair of positions considered when pricing a ride, in the order they
+// were considered. `start` and `end` are RFC 3339 in UTC, `speed_kmh` is
+// infinite when both positions share a timestamp, and `amount` is rounded on
+// its own with the tariff's rounding.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditedSegment {
+    pub id_ride: u32,
+    pub index: usize,
+    pub start: String,
+    pub end: String,
+    pub distance_km: f64,
+    pub speed_kmh: f64,
+    pub classification: Classification,
+    pub amount: Amount,
+}
+
+pub trait AuditSink: Send {
+    fn record(&mut self, segment: AuditedSegment) -> io::Result<()>;
+
+    // Called once every segment has been recorded.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> AuditSink for F
+where
+    F: FnMut(AuditedSegment) + Send,
+{
+    fn record(&mut self, segment: AuditedSegment) -> io::Result<()> {
+        self(segment);
+        Ok(())
+    }
+}
+
+// Writes segments as CSV, under a header row.
+pub struct CsvAuditSink<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> CsvAuditSink<W> {
+    pub fn new(output: W) -> Self {
+        CsvAuditSink {
+            writer: csv::Writer::from_writer(output),
+        }
+    }
+}
+
+impl<W: io::Write + Send> AuditSink for CsvAuditSink<W> {
+    fn record(&mut self, segment: AuditedSegment) -> io::Result<()> {
+        Ok(self.writer.serialize(segment)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Writes segments as JSON, one object per line.
+pub struct JsonLinesAuditSink<W: io::Write> {
+    writer: io::BufWriter<W>,
+}
+
+impl<W: io::Write> JsonLinesAuditSink<W> {
+    pub fn new(output: W) -> Self {
+        JsonLinesAuditSink {
+            writer: io::BufWriter::new(output),
+        }
+    }
+}
+
+impl<W: io::Write + Send> AuditSink for JsonLinesAuditSink<W> {
+    fn record(&mut self, segment: AuditedSegment) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &segment)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+fn audited_segment() -> AuditedSegment {
+    use super::money::Currency;
+
+    AuditedSegment {
+        id_ride: 7,
+        index: 2,
+        start: "2014-07-17T11:02:37Z".to_string(),
+        end: "2014-07-17T11:02:46.250Z".to_string(),
+        distance_km: 0.25,
+        speed_kmh: 100.0,
+        classification: Classification::DiscardedTooFast,
+        amount: Amount::new(0, Currency::EUR),
+    }
+}
+
+#[test]
+fn csv_audit_sink() {
+    let mut output = vec![];
+    {
+        let mut sink = CsvAuditSink::new(&mut output);
+        sink.record(audited_segment()).unwrap();
+        sink.flush().unwrap();
+    }
+
+    assert_eq!(
+        "id_ride,index,start,end,distance_km,speed_kmh,classification,amount\n\
+         7,2,2014-07-17T11:02:37Z,2014-07-17T11:02:46.250Z,0.25,100.0,discarded_too_fast,0.00\n",
+        String::from_utf8(output).unwrap()
+    );
+}
+
+#[test]
+fn json_lines_audit_sink() {
+    let mut output = vec![];
+    {
+        let mut sink = JsonLinesAuditSink::new(&mut output);
+        sink.record(audited_segment()).unwrap();
+        sink.record(audited_segment()).unwrap();
+        sink.flush().unwrap();
+    }
+
+    let line = r#"{"id_ride":7,"index":2,"start":"2014-07-17T11:02:37Z","end":"2014-07-17T11:02:46.250Z","distance_km":0.25,"speed_kmh":100.0,"classification":"discarded_too_fast","amount":"0.00"}"#;
+    assert_eq!(
+        format!("{}\n{}\n", line, line),
+        String::from_utf8(output).unwrap()
+    );
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+
+use super::money::{Amount, AmountFormat, Currency, FormattedAmount};
+
+// How a ride's fare was made up. Each amount is rounded on its own with the
+// tariff's rounding, so unless it rounds per segment they may not add up to
+// the fare to the cent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FareBreakdown {
+    pub flag: Amount,
+    pub day_km: f64,
+    pub day_amount: Amount,
+    pub night_km: f64,
+    pub night_amount: Amount,
+    pub idle_seconds: f64,
+    pub idle_amount: Amount,
+    pub discarded_segments: usize,
+    pub minimum_applied: bool,
+}
+
+impl FareBreakdown {
+    pub fn new(currency: Currency) -> Self {
+        let zero = Amount::new(0, currency);
+
+        FareBreakdown {
+            flag: zero,
+            day_km: 0.0,
+            day_amount: zero,
+            night_km: 0.0,
+            night_amount: zero,
+            idle_seconds: 0.0,
+            idle_amount: zero,
+            discarded_segments: 0,
+            minimum_applied: false,
+        }
+    }
+}
+
+// A row of the detailed output. Distances are rounded to the metre.
+#[derive(Serialize)]
+pub struct DetailedFare {
+    id_ride: u32,
+    fare_estimate: FormattedAmount,
+    flag: FormattedAmount,
+    day_km: f64,
+    day_amount: FormattedAmount,
+    night_km: f64,
+    night_amount: FormattedAmount,
+    idle_seconds: f64,
+    idle_amount: FormattedAmount,
+    discarded_segments: usize,
+    minimum_applied: bool,
+}
+
+impl DetailedFare {
+    pub fn new(
+        id: u32,
+        amount: Amount,
+        breakdown: &FareBreakdown,
+        amount_format: AmountFormat,
+    ) -> Self {
+        let round_km = |km: f64| (km * 1000.0).round() / 1000.0;
+        let format = |amount| FormattedAmount::new(amount, amount_format);
+
+        DetailedFare {
+            id_ride: id,
+            fare_estimate: format(amount),
+            flag: format(breakdown.flag),
+            day_km: round_km(breakdown.day_km),
+            day_amount: format(breakdown.day_amount),
+            night_km: round_km(breakdown.night_km),
+            night_amount: format(breakdown.night_amount),
+            idle_seconds: breakdown.idle_seconds,
+            idle_amount: format(breakdown.idle_amount),
+            discarded_segments: breakdown.discarded_segments,
+            minimum_applied: breakdown.minimum_applied,
+        }
+    }
+}
+
+#[test]
+fn detailed_fare_csv() {
+    let breakdown = FareBreakdown {
+        flag: Amount::new(130, Currency::EUR),
+        day_km: 12.3456789,
+        day_amount: Amount::new(914, Currency::EUR),
+        night_km: 0.0,
+        idle_seconds: 95.5,
+        idle_amount: Amount::new(31, Currency::EUR),
+        discarded_segments: 2,
+        ..FareBreakdown::new(Currency::EUR)
+    };
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer
+        .serialize(DetailedFare::new(
+            7,
+            Amount::new(1075, Currency::EUR),
+            &breakdown,
+            AmountFormat::Decimal,
+        ))
+        .unwrap();
+
+    assert_eq!(
+        "id_ride,fare_estimate,flag,day_km,day_amount,night_km,night_amount,idle_seconds,idle_amount,discarded_segments,minimum_applied\n\
+         7,10.75,1.30,12.346,9.14,0.0,0.00,95.5,0.31,2,false\n",
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    );
+}
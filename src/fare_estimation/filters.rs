@@ -0,0 +1,379 @@
+use serde::Deserialize;
+
+use super::audit::Classification;
+use super::geodesy::RideDistance;
+use super::{haversine, is_too_fast, Position, Segment, Tariff};
+
+// Which segments a tariff discards, each given as a table with a `kind`:
+//
+// - `max_speed`: faster than the tariff's `max_speed`, in km/h.
+// - `max_acceleration`: changing speed from the last kept segment by more
+//   than `max_acceleration` m/s², speeding up or slowing down.
+// - `min_displacement`: shorter than `min_distance` metres, so that a car
+//   standing still doesn't collect distance from GPS jitter. The time spent
+//   is priced with the next segment kept.
+// - `accuracy`: ending at a fix less accurate than `max_accuracy` metres.
+//   Fixes without an accuracy are kept.
+// - `spike`: ending at a fix the ride turns back from by more than
+//   `max_turn` degrees, when the legs to and from it are both longer than
+//   `min_leg` metres.
+//
+// A tariff discards fast segments only, by default.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Filter {
+    MaxSpeed,
+    MaxAcceleration { max_acceleration: f64 },
+    MinDisplacement { min_distance: f64 },
+    Accuracy { max_accuracy: f64 },
+    Spike { max_turn: f64, min_leg: f64 },
+}
+
+impl Filter {
+    pub(super) fn build(&self) -> Box<dyn SegmentFilter> {
+        match *self {
+            Filter::MaxSpeed => Box::new(MaxSpeed),
+            Filter::MaxAcceleration { max_acceleration } => {
+                Box::new(MaxAcceleration { max_acceleration })
+            }
+            Filter::MinDisplacement { min_distance } => Box::new(MinDisplacement { min_distance }),
+            Filter::Accuracy { max_accuracy } => Box::new(Accuracy { max_accuracy }),
+            Filter::Spike { max_turn, min_leg } => Box::new(Spike { max_turn, min_leg }),
+        }
+    }
+}
+
+// A segment from the last kept position to the next one. `previous` is the
+// last kept segment and `next` the position after `end`, if any. Other
+// distances are measured with `distance`, as the ride's segments are.
+pub(super) struct Candidate<'a> {
+    pub(super) tariff: &'a Tariff,
+    pub(super) distance: &'a RideDistance,
+    pub(super) start: &'a Position,
+    pub(super) end: &'a Position,
+    pub(super) next: Option<&'a Position>,
+    pub(super) segment: &'a Segment,
+    pub(super) previous: Option<&'a Segment>,
+}
+
+// Decides which segments are priced. When a segment is discarded, its end is
+// skipped: the next segment starts where the discarded one did.
+pub(super) trait SegmentFilter: Send + Sync {
+    fn keep(&self, candidate: &Candidate) -> bool;
+
+    // How the segments it discards are audited.
+    fn discarded(&self) -> Classification;
+}
+
+pub(super) struct MaxSpeed;
+
+impl SegmentFilter for MaxSpeed {
+    fn keep(&self, candidate: &Candidate) -> bool {
+        !is_too_fast(candidate.segment.speed(), candidate.tariff)
+    }
+
+    fn discarded(&self) -> Classification {
+        Classification::DiscardedTooFast
+    }
+}
+
+pub(super) struct MaxAcceleration {
+    max_acceleration: f64,
+}
+
+impl SegmentFilter for MaxAcceleration {
+    fn keep(&self, candidate: &Candidate) -> bool {
+        let previous = match candidate.previous {
+            Some(previous) => previous,
+            None => return true,
+        };
+        let change = (candidate.segment.speed() - previous.speed()) / 3.6;
+        let seconds = candidate.segment.duration_seconds();
+        if seconds <= 0.0 || !change.is_finite() {
+            return change == 0.0;
+        }
+
+        (change / seconds).abs() <= self.max_acceleration
+    }
+
+    fn discarded(&self) -> Classification {
+        Classification::DiscardedAcceleration
+    }
+}
+
+pub(super) struct MinDisplacement {
+    min_distance: f64,
+}
+
+impl SegmentFilter for MinDisplacement {
+    fn keep(&self, candidate: &Candidate) -> bool {
+        candidate.segment.distance_km * 1000.0 >= self.min_distance
+    }
+
+    fn discarded(&self) -> Classification {
+        Classification::DiscardedTooShort
+    }
+}
+
+pub(super) struct Accuracy {
+    max_accuracy: f64,
+}
+
+impl SegmentFilter for Accuracy {
+    fn keep(&self, candidate: &Candidate) -> bool {
+        match candidate.end.accuracy {
+            Some(accuracy) => accuracy <= self.max_accuracy,
+            None => true,
+        }
+    }
+
+    fn discarded(&self) -> Classification {
+        Classification::DiscardedInaccurate
+    }
+}
+
+pub(super) struct Spike {
+    max_turn: f64,
+    min_leg: f64,
+}
+
+impl SegmentFilter for Spike {
+    fn keep(&self, candidate: &Candidate) -> bool {
+        let next = match candidate.next {
+            Some(next) => next,
+            None => return true,
+        };
+        let (start, end) = (&candidate.start.location, &candidate.end.location);
+        let leg_to = candidate.segment.distance_km * 1000.0;
+        let leg_from = candidate.distance.peek_km(end, &next.location) * 1000.0;
+        if leg_to <= self.min_leg || leg_from <= self.min_leg {
+            return true;
+        }
+
+        turn_degrees(start, end, &next.location) <= self.max_turn
+    }
+
+    fn discarded(&self) -> Classification {
+        Classification::DiscardedSpike
+    }
+}
+
+// How far the heading from `a` to `b` turns to head on to `c`, from 0 for
+// straight on to 180 for straight back.
+fn turn_degrees(a: &haversine::Location, b: &haversine::Location, c: &haversine::Location) -> f64 {
+    let scale = b.latitude.to_radians().cos();
+    let (x1, y1) = ((b.longitude - a.longitude) * scale, b.latitude - a.latitude);
+    let (x2, y2) = ((c.longitude - b.longitude) * scale, c.latitude - b.latitude);
+    let cosine = (x1 * x2 + y1 * y2) / (x1.hypot(y1) * x2.hypot(y2));
+
+    cosine.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Distance;
+    use super::*;
+    use chrono::prelude::*;
+
+    fn at(seconds: i64, latitude: f64, longitude: f64) -> Position {
+        Position {
+            datetime: Utc.timestamp(1405594957 + seconds, 0),
+            location: haversine::Location {
+                latitude,
+                longitude,
+            },
+            accuracy: None,
+        }
+    }
+
+    fn segment(start: &Position, end: &Position) -> Segment {
+        Segment {
+            start: start.datetime,
+            end: end.datetime,
+            distance_km: haversine::distance_km(&start.location, &end.location),
+        }
+    }
+
+    // Whether `filter` keeps the segment from `start` to `end`, after the
+    // segment from `before` to `start` if given.
+    fn keeps(
+        filter: &Filter,
+        before: Option<&Position>,
+        start: &Position,
+        end: &Position,
+        next: Option<&Position>,
+    ) -> bool {
+        let tariff = Tariff::default();
+        let previous = before.map(|before| segment(before, start));
+        let candidate = Candidate {
+            tariff: &tariff,
+            distance: &tariff.distance.for_ride(tariff.flat_earth),
+            start,
+            end,
+            next,
+            segment: &segment(start, end),
+            previous: previous.as_ref(),
+        };
+        filter.build().keep(&candidate)
+    }
+
+    #[test]
+    fn max_speed() {
+        let start = at(0, 37.96, 23.72);
+
+        // About 0.0009° of latitude a minute is 6 km/h.
+        for (latitude, want) in [(37.96, true), (37.97, true), (37.99, false)] {
+            let end = at(60, latitude, 23.72);
+            assert_eq!(want, keeps(&Filter::MaxSpeed, None, &start, &end, None));
+        }
+        let moved_at_once = at(0, 37.961, 23.72);
+        assert!(!keeps(
+            &Filter::MaxSpeed,
+            None,
+            &start,
+            &moved_at_once,
+            None
+        ));
+    }
+
+    #[test]
+    fn max_acceleration() {
+        let filter = Filter::MaxAcceleration {
+            max_acceleration: 3.0,
+        };
+        let (before, start) = (at(0, 37.96, 23.72), at(10, 37.9609, 23.72));
+
+        // 10 m/s, then 20 m/s, then 60 m/s, over 10 seconds each.
+        assert!(keeps(
+            &filter,
+            Some(&before),
+            &start,
+            &at(20, 37.9627, 23.72),
+            None
+        ));
+        assert!(!keeps(
+            &filter,
+            Some(&before),
+            &start,
+            &at(20, 37.9663, 23.72),
+            None
+        ));
+        // Stopping dead is as bad as speeding off.
+        assert!(!keeps(
+            &filter,
+            Some(&before),
+            &start,
+            &at(12, 37.9609, 23.72),
+            None
+        ));
+        assert!(keeps(&filter, None, &start, &at(20, 37.9663, 23.72), None));
+    }
+
+    #[test]
+    fn min_displacement() {
+        let filter = Filter::MinDisplacement { min_distance: 20.0 };
+        let start = at(0, 37.96, 23.72);
+
+        assert!(!keeps(&filter, None, &start, &at(5, 37.96009, 23.72), None));
+        assert!(keeps(&filter, None, &start, &at(5, 37.9603, 23.72), None));
+    }
+
+    #[test]
+    fn accuracy() {
+        let filter = Filter::Accuracy { max_accuracy: 25.0 };
+        let start = at(0, 37.96, 23.72);
+        let with_accuracy = |accuracy| Position {
+            accuracy,
+            ..at(10, 37.9601, 23.72)
+        };
+
+        assert!(keeps(
+            &filter,
+            None,
+            &start,
+            &with_accuracy(Some(25.0)),
+            None
+        ));
+        assert!(!keeps(
+            &filter,
+            None,
+            &start,
+            &with_accuracy(Some(40.0)),
+            None
+        ));
+        assert!(keeps(&filter, None, &start, &with_accuracy(None), None));
+    }
+
+    #[test]
+    fn spike() {
+        let filter = Filter::Spike {
+            max_turn: 150.0,
+            min_leg: 50.0,
+        };
+        let start = at(0, 37.96, 23.72);
+        let spike = at(5, 37.962, 23.72);
+
+        // Out 220 m and straight back.
+        let back = at(10, 37.9601, 23.72);
+        assert!(!keeps(&filter, None, &start, &spike, Some(&back)));
+        // Turning a corner.
+        let corner = at(10, 37.962, 23.7225);
+        assert!(keeps(&filter, None, &start, &spike, Some(&corner)));
+        // Back, but too close to tell from jitter.
+        let near = at(5, 37.9603, 23.72);
+        assert!(keeps(&filter, None, &start, &near, Some(&start)));
+        assert!(keeps(&filter, None, &start, &spike, None));
+    }
+
+    #[test]
+    fn spike_legs_are_measured_like_the_ride() {
+        let (start, spike) = (at(0, 37.96, 23.72), at(5, 37.962, 23.72));
+        let back = at(10, 37.96, 23.72);
+        let km = |distance: Distance| distance.km(&start.location, &spike.location);
+        let (spherical, ellipsoidal) = (km(Distance::Haversine), km(Distance::Wgs84));
+        assert!(spherical > ellipsoidal);
+
+        // Legs too short to tell from jitter on the ellipsoid only.
+        let filter = Filter::Spike {
+            max_turn: 150.0,
+            min_leg: (spherical + ellipsoidal) / 2.0 * 1000.0,
+        };
+        // Whatever the tariff says, as the segmenter measures the ride with its
+        // own distance, e.g. on a flat Earth.
+        let tariff = Tariff::default();
+        for (distance, want) in [(Distance::Haversine, false), (Distance::Wgs84, true)] {
+            let mut ride_distance = distance.for_ride(true);
+            let segment = Segment {
+                start: start.datetime,
+                end: spike.datetime,
+                distance_km: ride_distance.km(&start.location, &spike.location),
+            };
+            let candidate = Candidate {
+                tariff: &tariff,
+                distance: &ride_distance,
+                start: &start,
+                end: &spike,
+                next: Some(&back),
+                segment: &segment,
+                previous: None,
+            };
+            assert_eq!(want, filter.build().keep(&candidate), "{:?}", distance);
+        }
+    }
+
+    #[test]
+    fn turns() {
+        let (a, b) = (at(0, 37.96, 23.72), at(0, 37.97, 23.72));
+        let turn = |latitude, longitude| {
+            turn_degrees(
+                &a.location,
+                &b.location,
+                &at(0, latitude, longitude).location,
+            )
+        };
+
+        assert!(turn(37.98, 23.72).abs() < 1e-6);
+        assert!((turn(37.97, 23.73) - 90.0).abs() < 1e-6);
+        assert!((turn(37.95, 23.72) - 180.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,215 @@
+use serde::Deserialize;
+
+use super::haversine::{self, FlatEarth, Location};
+
+// The WGS-84 ellipsoid, as used by GPS.
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+const WGS84_B: f64 = WGS84_A * (1.0 - WGS84_F);
+
+const MAX_ITERATIONS: usize = 200;
+const CONVERGENCE: f64 = 1e-12;
+
+// How a tariff measures the distance between two positions. `Haversine`
+// takes the Earth as a sphere of radius 6371 km, which is up to about 0.5%
+// off; `Wgs84` measures along the WGS-84 ellipsoid, to well under a
+// millimetre.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Distance {
+    #[default]
+    Haversine,
+    Wgs84,
+}
+
+impl Distance {
+    pub fn km(&self, start: &Location, end: &Location) -> f64 {
+        match self {
+            Distance::Haversine => haversine::distance_km(start, end),
+            Distance::Wgs84 => vincenty_km(start, end),
+        }
+    }
+
+    // Measures the consecutive positions of one ride. With haversine and
+    // `flat_earth`, close positions are measured on a flat Earth, which is
+    // much faster and just as accurate for them.
+    pub(super) fn for_ride(&self, flat_earth: bool) -> RideDistance {
+        RideDistance {
+            distance: *self,
+            flat_earth,
+            flat: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct RideDistance {
+    distance: Distance,
+    flat_earth: bool,
+    flat: Option<FlatEarth>,
+}
+
+impl RideDistance {
+    pub(super) fn km(&mut self, start: &Location, end: &Location) -> f64 {
+        match self.distance {
+            Distance::Haversine if !self.flat_earth => haversine::distance_km(start, end),
+            Distance::Haversine => self
+                .flat
+                .get_or_insert_with(|| FlatEarth::new(start.latitude))
+                .distance_km(start, end),
+            Distance::Wgs84 => vincenty_km(start, end),
+        }
+    }
+
+    // As `km`, but leaves the flat Earth where it was, to measure ahead of
+    // the ride.
+    pub(super) fn peek_km(&self, start: &Location, end: &Location) -> f64 {
+        self.clone().km(start, end)
+    }
+}
+
+// Vincenty's inverse formula. It doesn't converge for nearly antipodal
+// points, which no ride has; those fall back to haversine.
+pub fn vincenty_km(start: &Location, end: &Location) -> f64 {
+    let l = (end.longitude - start.longitude).to_radians();
+    let u1 = ((1.0 - WGS84_F) * start.latitude.to_radians().tan()).atan();
+    let u2 = ((1.0 - WGS84_F) * end.latitude.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..MAX_ITERATIONS {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            return 0.0;
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos2_alpha = 1.0 - sin_alpha * sin_alpha;
+        // Along the equator, cos2_alpha is zero.
+        let cos_2sigma_m = if cos2_alpha == 0.0 {
+            0.0
+        } else {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos2_alpha
+        };
+        let c = WGS84_F / 16.0 * cos2_alpha * (4.0 + WGS84_F * (4.0 - 3.0 * cos2_alpha));
+        let previous = lambda;
+        lambda = l
+            + (1.0 - c)
+                * WGS84_F
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2sigma_m
+                            + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)));
+
+        if (lambda - previous).abs() < CONVERGENCE {
+            let u_squared =
+                cos2_alpha * (WGS84_A * WGS84_A - WGS84_B * WGS84_B) / (WGS84_B * WGS84_B);
+            let a = 1.0
+                + u_squared / 16384.0
+                    * (4096.0 + u_squared * (-768.0 + u_squared * (320.0 - 175.0 * u_squared)));
+            let b = u_squared / 1024.0
+                * (256.0 + u_squared * (-128.0 + u_squared * (74.0 - 47.0 * u_squared)));
+            let delta_sigma = b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)
+                            - b / 6.0
+                                * cos_2sigma_m
+                                * (-3.0 + 4.0 * sin_sigma * sin_sigma)
+                                * (-3.0 + 4.0 * cos_2sigma_m * cos_2sigma_m)));
+
+            return WGS84_B * a * (sigma - delta_sigma) / 1000.0;
+        }
+    }
+
+    haversine::distance_km(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(latitude: f64, longitude: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn degrees(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+        degrees.signum() * (degrees.abs() + minutes / 60.0 + seconds / 3600.0)
+    }
+
+    #[test]
+    fn vincenty_reference_values() {
+        // Distances in metres, from Geoscience Australia's worked example and
+        // the WGS-84 equatorial radius and meridian quadrant.
+        for (start, end, metres) in [
+            (
+                location(
+                    degrees(-37.0, 57.0, 3.72030),
+                    degrees(144.0, 25.0, 29.52440),
+                ),
+                location(
+                    degrees(-37.0, 39.0, 10.15610),
+                    degrees(143.0, 55.0, 35.38390),
+                ),
+                54_972.271,
+            ),
+            (location(0.0, 0.0), location(0.0, 1.0), 111_319.491),
+            (location(0.0, 0.0), location(90.0, 0.0), 10_001_965.729),
+        ] {
+            let km = vincenty_km(&start, &end);
+            assert!((km * 1000.0 - metres).abs() < 0.001, "{} {}", km, metres);
+            assert!((km - vincenty_km(&end, &start)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn vincenty_edge_cases() {
+        let athens = location(37.96, 23.72);
+        assert_eq!(0.0, vincenty_km(&athens, &athens));
+
+        // Nearly antipodal, where the iteration doesn't converge.
+        let (start, end) = (location(0.0, 0.0), location(0.5, 179.7));
+        let km = vincenty_km(&start, &end);
+        assert!(km.is_finite());
+        assert!((km - haversine::distance_km(&start, &end)).abs() / km < 0.01);
+    }
+
+    #[test]
+    fn distances_agree_closely() {
+        let (start, end) = (location(37.936, 23.944), location(37.976, 23.736));
+        let spherical = Distance::Haversine.km(&start, &end);
+        let ellipsoidal = Distance::Wgs84.km(&start, &end);
+
+        assert_eq!(haversine::distance_km(&start, &end), spherical);
+        assert!((spherical - ellipsoidal).abs() / ellipsoidal < 0.005);
+        assert!(spherical != ellipsoidal);
+    }
+
+    #[test]
+    fn rides_measure_close_positions_on_a_flat_earth() {
+        let (start, end) = (location(37.9600, 23.7200), location(37.9630, 23.7240));
+        let spherical = haversine::distance_km(&start, &end);
+
+        let flat = Distance::Haversine.for_ride(true).km(&start, &end);
+        assert!(flat != spherical);
+        assert!((flat - spherical).abs() / spherical < 0.0002);
+        assert_eq!(
+            spherical,
+            Distance::Haversine.for_ride(false).km(&start, &end)
+        );
+        assert_eq!(
+            vincenty_km(&start, &end),
+            Distance::Wgs84.for_ride(true).km(&start, &end)
+        );
+    }
+}
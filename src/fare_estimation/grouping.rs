@@ -0,0 +1,514 @@
+use chrono::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc;
+
+use super::input::PositionReader;
+use super::{haversine, Position, ReadError, Ride};
+
+pub const DEFAULT_MAX_POSITIONS_IN_MEMORY: usize = 1_000_000;
+
+// How rows are put together into rides.
+//
+// `Consecutive` takes each run of rows with the same id as a ride, so a ride
+// whose rows are split up is priced once per run. `RejectReappearing` does
+// the same but rejects the rows of any run after the first, with a
+// `ReappearingRideError`; it remembers the id of every ride it has finished,
+// so its memory grows with the number of rides read, by a few bytes each.
+// `ById` gathers every row of a ride wherever it is, keeping them in input
+// order, and sends rides out in order of their id. It holds up to
+// `max_positions_in_memory` positions, spilling sorted runs of them to
+// temporary files beyond that.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RideGrouping {
+    #[default]
+    Consecutive,
+    RejectReappearing,
+    ById {
+        max_positions_in_memory: usize,
+    },
+}
+
+impl FromStr for RideGrouping {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "consecutive" => Ok(RideGrouping::Consecutive),
+            "reject-reappearing" => Ok(RideGrouping::RejectReappearing),
+            "by-id" => Ok(RideGrouping::ById {
+                max_positions_in_memory: DEFAULT_MAX_POSITIONS_IN_MEMORY,
+            }),
+            _ => Err(format!("unknown ride grouping {:?}", name)),
+        }
+    }
+}
+
+// Rides are handed over from a blocking thread, hence `blocking_send`.
+type Rides = mpsc::Sender<Result<Ride, ReadError>>;
+
+type SortedRun<'a> = Box<dyn Iterator<Item = io::Result<(u32, Position)>> + 'a>;
+
+// Rows that can't be parsed are sent as errors and skipped. Only failing to
+// read the input at all stops the reading.
+pub(super) fn group_rides(
+    positions_reader: impl PositionReader,
+    grouping: RideGrouping,
+    parsed_records_tx: Rides,
+) -> Result<(), ReadError> {
+    match grouping {
+        RideGrouping::Consecutive => group_consecutive(positions_reader, false, parsed_records_tx),
+        RideGrouping::RejectReappearing => {
+            group_consecutive(positions_reader, true, parsed_records_tx)
+        }
+        RideGrouping::ById {
+            max_positions_in_memory,
+        } => group_by_id(positions_reader, max_positions_in_memory, parsed_records_tx),
+    }
+}
+
+fn group_consecutive(
+    mut positions_reader: impl PositionReader,
+    reject_reappearing: bool,
+    parsed_records_tx: Rides,
+) -> Result<(), ReadError> {
+    let mut current_ride_id: Option<u32> = None;
+    let mut positions: Vec<Position> = vec![];
+    let mut finished_rides = HashSet::new();
+
+    while let Some(row) = positions_reader.next_row()? {
+        let row = row.and_then(|(id, position)| match finished_rides.contains(&id) {
+            true => Err(positions_reader.row_error(ReadError::ReappearingRideError { id })),
+            false => Ok((id, position)),
+        });
+        let (id, position) = match row {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                // The receiver is only gone if fare calculation stopped early.
+                if parsed_records_tx.blocking_send(Err(err)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        if let Some(cri) = current_ride_id {
+            if cri != id {
+                if reject_reappearing {
+                    finished_rides.insert(cri);
+                }
+                let ride = Ride { id: cri, positions };
+                if parsed_records_tx.blocking_send(Ok(ride)).is_err() {
+                    return Ok(());
+                }
+                positions = vec![];
+            }
+        }
+
+        positions.push(position);
+        current_ride_id = Some(id);
+    }
+
+    if let Some(id) = current_ride_id {
+        let _ = parsed_records_tx.blocking_send(Ok(Ride { id, positions }));
+    }
+
+    Ok(())
+}
+
+// An external merge sort on ride id. Sorting is stable, and runs are merged
+// in the order they were spilled, so each ride keeps its rows in input order.
+// Runs are merged at most `MAX_RUNS_PER_MERGE` at a time, so only that many
+// spill files are ever open.
+fn group_by_id(
+    mut positions_reader: impl PositionReader,
+    max_positions_in_memory: usize,
+    parsed_records_tx: Rides,
+) -> Result<(), ReadError> {
+    let mut buffer: Vec<(u32, Position)> = vec![];
+    let mut runs: Vec<SpillFile> = vec![];
+
+    while let Some(row) = positions_reader.next_row()? {
+        match row {
+            Ok(parsed) => buffer.push(parsed),
+            Err(err) => {
+                if parsed_records_tx.blocking_send(Err(err)).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+        }
+
+        if buffer.len() >= max_positions_in_memory.max(1) {
+            buffer.sort_by_key(|(id, _)| *id);
+            runs.push(SpillFile::write(buffer.drain(..).map(Ok))?);
+        }
+    }
+    buffer.sort_by_key(|(id, _)| *id);
+
+    // Leaves room in the last merge for the positions still in memory.
+    let runs = merge_runs(runs, MAX_RUNS_PER_MERGE)?;
+    let mut sources: Vec<SortedRun> = vec![];
+    for run in &runs {
+        sources.push(Box::new(run.read()?));
+    }
+    sources.push(Box::new(buffer.into_iter().map(Ok)));
+
+    let mut current: Option<Ride> = None;
+    for row in Merge::new(sources)? {
+        let (id, position) = row?;
+        match &mut current {
+            Some(ride) if ride.id == id => ride.positions.push(position),
+            _ => {
+                let ride = Ride {
+                    id,
+                    positions: vec![position],
+                };
+                if let Some(ride) = current.replace(ride) {
+                    if parsed_records_tx.blocking_send(Ok(ride)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(ride) = current {
+        let _ = parsed_records_tx.blocking_send(Ok(ride));
+    }
+
+    Ok(())
+}
+
+const MAX_RUNS_PER_MERGE: usize = 64;
+
+// Merges neighbouring runs `fan_in` at a time into longer runs, until fewer
+// than `fan_in` are left. Merged runs keep the order of the runs they were
+// made from.
+fn merge_runs(mut runs: Vec<SpillFile>, fan_in: usize) -> io::Result<Vec<SpillFile>> {
+    while runs.len() >= fan_in {
+        let mut merged = vec![];
+        for group in runs.chunks(fan_in) {
+            let mut sources: Vec<SortedRun> = vec![];
+            for run in group {
+                sources.push(Box::new(run.read()?));
+            }
+            merged.push(SpillFile::write(Merge::new(sources)?)?);
+        }
+        runs = merged;
+    }
+
+    Ok(runs)
+}
+
+// Merges runs sorted by ride id. Positions of the same ride come from the
+// earliest run first, so merging is stable.
+struct Merge<'a> {
+    sources: Vec<SortedRun<'a>>,
+    heads: Vec<Option<(u32, Position)>>,
+    heap: BinaryHeap<Reverse<(u32, usize)>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(mut sources: Vec<SortedRun<'a>>) -> io::Result<Self> {
+        let mut heap = BinaryHeap::new();
+        let mut heads = vec![];
+        for (index, source) in sources.iter_mut().enumerate() {
+            let head = source.next().transpose()?;
+            if let Some((id, _)) = head {
+                heap.push(Reverse((id, index)));
+            }
+            heads.push(head);
+        }
+
+        Ok(Merge {
+            sources,
+            heads,
+            heap,
+        })
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<(u32, Position)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, index)) = self.heap.pop()?;
+        let head = self.heads[index].take();
+        match self.sources[index].next().transpose() {
+            Ok(next) => {
+                if let Some((next_id, _)) = next {
+                    self.heap.push(Reverse((next_id, index)));
+                }
+                self.heads[index] = next;
+            }
+            Err(err) => return Some(Err(err)),
+        }
+
+        head.map(Ok)
+    }
+}
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Each position takes `RECORD_SIZE` bytes: the ride id, the timestamp as
+// seconds and nanoseconds, then latitude, longitude and accuracy, NaN if
+// unknown.
+const RECORD_SIZE: usize = 4 + 8 + 4 + 8 + 8 + 8;
+
+// A sorted run of positions in a temporary file, removed on drop.
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    fn write(positions: impl IntoIterator<Item = io::Result<(u32, Position)>>) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "fare_estimation-{}-{}.run",
+            process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let spill_file = SpillFile { path };
+
+        let mut writer = BufWriter::new(file);
+        for read in positions {
+            let (id, position) = read?;
+            writer.write_all(&id.to_le_bytes())?;
+            writer.write_all(&position.datetime.timestamp().to_le_bytes())?;
+            writer.write_all(&position.datetime.timestamp_subsec_nanos().to_le_bytes())?;
+            writer.write_all(&position.location.latitude.to_le_bytes())?;
+            writer.write_all(&position.location.longitude.to_le_bytes())?;
+            writer.write_all(&position.accuracy.unwrap_or(f64::NAN).to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        Ok(spill_file)
+    }
+
+    fn read(&self) -> io::Result<impl Iterator<Item = io::Result<(u32, Position)>>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+
+        Ok(std::iter::from_fn(move || {
+            let mut record = [0; RECORD_SIZE];
+            match reader.read_exact(&mut record) {
+                Ok(()) => Some(Ok(decode(&record))),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> (u32, Position) {
+    let id = u32::from_le_bytes(record[0..4].try_into().unwrap());
+    let seconds = i64::from_le_bytes(record[4..12].try_into().unwrap());
+    let nanos = u32::from_le_bytes(record[12..16].try_into().unwrap());
+    let latitude = f64::from_le_bytes(record[16..24].try_into().unwrap());
+    let longitude = f64::from_le_bytes(record[24..32].try_into().unwrap());
+    let accuracy = f64::from_le_bytes(record[32..40].try_into().unwrap());
+
+    (
+        id,
+        Position {
+            datetime: Utc.timestamp(seconds, nanos),
+            location: haversine::Location {
+                latitude,
+                longitude,
+            },
+            accuracy: Some(accuracy).filter(|accuracy| !accuracy.is_nan()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::input::{CsvInput, CsvPositions};
+    use super::super::TimestampFormat;
+    use super::*;
+
+    const INPUT: &str = "7,37.96,23.72,1405594957\n\
+                         7,37.97,23.73,1405594966\n\
+                         8,37.96,23.72,1405594970\n\
+                         7,37.98,23.74,1405594980\n\
+                         3,37.96,23.72,1405594990\n\
+                         8,37.97,23.73,1405595000\n";
+
+    // Each ride as its id and the latitudes of its positions, and each
+    // rejected row as its line number.
+    fn group(grouping: RideGrouping) -> Vec<Result<(u32, Vec<f64>), u64>> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let reader = CsvPositions::new(
+            INPUT.as_bytes(),
+            CsvInput::default(),
+            TimestampFormat::default(),
+        );
+        group_rides(reader, grouping, tx).unwrap();
+
+        std::iter::from_fn(|| rx.blocking_recv())
+            .map(|ride| match ride {
+                Ok(ride) => Ok((
+                    ride.id,
+                    ride.positions
+                        .iter()
+                        .map(|position| position.location.latitude)
+                        .collect(),
+                )),
+                Err(ReadError::RowError { line, error, .. }) => {
+                    assert_eq!("reappearing_ride", error.kind());
+                    Err(line)
+                }
+                Err(err) => panic!("unexpected error {}", err),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_groups_consecutive_rows() {
+        assert_eq!(
+            vec![
+                Ok((7, vec![37.96, 37.97])),
+                Ok((8, vec![37.96])),
+                Ok((7, vec![37.98])),
+                Ok((3, vec![37.96])),
+                Ok((8, vec![37.97])),
+            ],
+            group(RideGrouping::Consecutive)
+        );
+    }
+
+    #[test]
+    fn it_rejects_reappearing_rides() {
+        assert_eq!(
+            vec![
+                Ok((7, vec![37.96, 37.97])),
+                Err(4),
+                Ok((8, vec![37.96])),
+                Err(6),
+                Ok((3, vec![37.96])),
+            ],
+            group(RideGrouping::RejectReappearing)
+        );
+    }
+
+    #[test]
+    fn it_groups_rows_by_id() {
+        let want = vec![
+            Ok((3, vec![37.96])),
+            Ok((7, vec![37.96, 37.97, 37.98])),
+            Ok((8, vec![37.96, 37.97])),
+        ];
+
+        for max_positions_in_memory in [1, 2, 4, 100] {
+            assert_eq!(
+                want,
+                group(RideGrouping::ById {
+                    max_positions_in_memory
+                }),
+                "{}",
+                max_positions_in_memory
+            );
+        }
+    }
+
+    #[test]
+    fn it_merges_runs_a_few_at_a_time() {
+        // Rides 1 and 2 in each of ten runs, at the latitude of their run.
+        let at = |run: usize| Position {
+            datetime: Utc.timestamp(1405594957, 0),
+            location: haversine::Location {
+                latitude: run as f64,
+                longitude: 23.72,
+            },
+            accuracy: None,
+        };
+        let runs: Vec<SpillFile> = (0..10)
+            .map(|run| SpillFile::write(vec![Ok((1, at(run))), Ok((2, at(run)))]).unwrap())
+            .collect();
+
+        let runs = merge_runs(runs, 3).unwrap();
+        assert_eq!(2, runs.len());
+
+        let mut sources: Vec<SortedRun> = vec![];
+        for run in &runs {
+            sources.push(Box::new(run.read().unwrap()));
+        }
+        let merged: Vec<(u32, f64)> = Merge::new(sources)
+            .unwrap()
+            .map(|read| {
+                let (id, position) = read.unwrap();
+                (id, position.location.latitude)
+            })
+            .collect();
+        let want: Vec<(u32, f64)> = [1, 2]
+            .iter()
+            .flat_map(|&id| (0..10).map(move |run| (id, run as f64)))
+            .collect();
+        assert_eq!(want, merged);
+    }
+
+    #[test]
+    fn spill_files_keep_positions() {
+        let position = Position {
+            datetime: Utc.timestamp(1405594957, 250_000_000),
+            location: haversine::Location {
+                latitude: 37.966660,
+                longitude: -23.728308,
+            },
+            accuracy: None,
+        };
+        let accurate = Position {
+            accuracy: Some(12.5),
+            ..position.clone()
+        };
+        let spill_file = SpillFile::write(vec![Ok((7, position)), Ok((8, accurate))]).unwrap();
+        let path = spill_file.path.clone();
+
+        let positions: Vec<_> = spill_file
+            .read()
+            .unwrap()
+            .map(|read| {
+                let (id, position) = read.unwrap();
+                (
+                    id,
+                    position.datetime,
+                    position.location.longitude,
+                    position.accuracy,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (7, Utc.timestamp(1405594957, 250_000_000), -23.728308, None),
+                (
+                    8,
+                    Utc.timestamp(1405594957, 250_000_000),
+                    -23.728308,
+                    Some(12.5)
+                ),
+            ],
+            positions
+        );
+
+        drop(spill_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,770 @@
+use serde::Deserialize;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::rejection::Rejection;
+use super::{parse_record, Position, ReadError, Record, Ride, Tariff, TimestampFormat};
+
+const RECORD_LENGTH: usize = 4;
+// How much of the input is kept behind the row being read before it's
+// dropped, so that it isn't moved for every row.
+const RECORDED_SLACK: usize = 64 * 1024;
+
+// How positions are laid out in the input.
+//
+// `Csv` is one position per row, see `CsvInput`. `JsonLines` is one object
+// per line, e.g. `{"id_ride":1,"lat":37.96,"lng":23.72,"timestamp":1405594957}`;
+// other keys are ignored and blank lines skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputFormat {
+    Csv(CsvInput),
+    JsonLines,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat::Csv(CsvInput::default())
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "csv" => Ok(InputFormat::Csv(CsvInput::default())),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("unknown input format {:?}", name)),
+        }
+    }
+}
+
+// Without `columns`, rows have no header and hold exactly
+// `id,latitude,longitude,timestamp`. With `columns`, the first row is a header
+// and those four, and the accuracy if there's a column for it, are taken
+// from the named columns, wherever they are; any other columns are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvInput {
+    pub delimiter: u8,
+    pub columns: Option<CsvColumns>,
+}
+
+impl Default for CsvInput {
+    fn default() -> Self {
+        CsvInput {
+            delimiter: b',',
+            columns: None,
+        }
+    }
+}
+
+// Header names of the columns holding each value. They default to the keys
+// of the JSON Lines format, except that accuracies are only read if their
+// column is named.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvColumns {
+    pub id: String,
+    pub latitude: String,
+    pub longitude: String,
+    pub timestamp: String,
+    pub accuracy: Option<String>,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        CsvColumns {
+            id: "id_ride".to_string(),
+            latitude: "lat".to_string(),
+            longitude: "lng".to_string(),
+            timestamp: "timestamp".to_string(),
+            accuracy: None,
+        }
+    }
+}
+
+// A position read from the input, tagged with its ride id, or why the row
+// holding it was rejected.
+pub(super) type Row = Result<(u32, Position), ReadError>;
+
+pub(super) trait PositionReader {
+    // The next row, or `None` once the input is exhausted. Rows that can't be
+    // parsed come back as `RowError`s; errors reading the input at all are
+    // returned as `Err`.
+    fn next_row(&mut self) -> Result<Option<Row>, ReadError>;
+
+    // Rejects the row last read, wrapping `error` in a `RowError`.
+    fn row_error(&self, error: ReadError) -> ReadError;
+}
+
+pub(super) struct CsvPositions<R: io::Read> {
+    reader: csv::Reader<Recorder<BufReader<R>>>,
+    record: csv::ByteRecord,
+    columns: Option<CsvColumns>,
+    timestamps: TimestampFormat,
+    // Where each value is in a row, once the header has been read.
+    indices: Option<[usize; RECORD_LENGTH]>,
+    accuracy_index: Option<usize>,
+    header_length: usize,
+}
+
+impl<R: io::Read> CsvPositions<R> {
+    pub(super) fn new(input: R, format: CsvInput, timestamps: TimestampFormat) -> Self {
+        CsvPositions {
+            reader: csv::ReaderBuilder::new()
+                .has_headers(format.columns.is_some())
+                .delimiter(format.delimiter)
+                .flexible(true)
+                .from_reader(Recorder::new(BufReader::new(input))),
+            record: csv::ByteRecord::new(),
+            columns: format.columns,
+            timestamps,
+            indices: None,
+            accuracy_index: None,
+            header_length: 0,
+        }
+    }
+
+    // Finds the named columns in the header. Returns false if there is no
+    // header, i.e. the input is empty.
+    fn read_header(&mut self, columns: &CsvColumns) -> Result<bool, ReadError> {
+        let header = self.reader.byte_headers()?;
+        if header.is_empty() {
+            return Ok(false);
+        }
+
+        let find = |name: &String| {
+            header
+                .iter()
+                .position(|column| column == name.as_bytes())
+                .ok_or_else(|| ReadError::MissingColumnError {
+                    column: name.clone(),
+                })
+        };
+        let mut indices = [0; RECORD_LENGTH];
+        for (index, name) in indices.iter_mut().zip([
+            &columns.id,
+            &columns.latitude,
+            &columns.longitude,
+            &columns.timestamp,
+        ]) {
+            *index = find(name)?;
+        }
+        self.accuracy_index = columns.accuracy.as_ref().map(find).transpose()?;
+        self.header_length = header.len();
+        self.indices = Some(indices);
+        Ok(true)
+    }
+}
+
+impl<R: io::Read> PositionReader for CsvPositions<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, ReadError> {
+        if let (Some(columns), None) = (self.columns.take(), self.indices) {
+            if !self.read_header(&columns)? {
+                return Ok(None);
+            }
+        }
+        if let Some(position) = self.record.position() {
+            self.reader.get_mut().forget_before(position.byte());
+        }
+        if !self.reader.read_byte_record(&mut self.record)? {
+            return Ok(None);
+        }
+
+        let row = match self.indices {
+            Some(indices) => parse_mapped_record(
+                &self.record,
+                &indices,
+                self.accuracy_index,
+                self.header_length,
+                self.timestamps,
+            ),
+            None => parse_byte_record(&self.record, self.timestamps),
+        };
+        Ok(Some(row.map_err(|err| self.row_error(err))))
+    }
+
+    fn row_error(&self, error: ReadError) -> ReadError {
+        let (line, mut byte) = match self.record.position() {
+            Some(position) => (position.line(), position.byte()),
+            None => (0, 0),
+        };
+        let end = self.reader.position().byte();
+        let mut raw = self.reader.get_ref().recorded(byte, end);
+        // After a CRLF, rows are taken to start at its LF.
+        while let [b'\r' | b'\n', rest @ ..] = raw {
+            raw = rest;
+            byte += 1;
+        }
+
+        ReadError::RowError {
+            line,
+            byte,
+            raw: String::from_utf8_lossy(trim_line(raw)).into_owned(),
+            error: Box::new(error),
+        }
+    }
+}
+
+// Keeps what the CSV reader has read of the input, from the start of the
+// row being read on, so that rejected rows can be reported as they were
+// written, with their delimiters and quotes.
+struct Recorder<R> {
+    input: R,
+    recorded: Vec<u8>,
+    // Where `recorded` starts in the input.
+    offset: u64,
+}
+
+impl<R> Recorder<R> {
+    fn new(input: R) -> Self {
+        Recorder {
+            input,
+            recorded: vec![],
+            offset: 0,
+        }
+    }
+
+    // The input from byte `start` up to `end`, both already read.
+    fn recorded(&self, start: u64, end: u64) -> &[u8] {
+        let from = start.saturating_sub(self.offset) as usize;
+        let to = (end.saturating_sub(self.offset) as usize).min(self.recorded.len());
+        &self.recorded[from.min(to)..to]
+    }
+
+    // Rows before `start` won't be asked for again.
+    fn forget_before(&mut self, start: u64) {
+        let forgotten = start.saturating_sub(self.offset) as usize;
+        if forgotten > RECORDED_SLACK {
+            self.recorded.drain(..forgotten);
+            self.offset = start;
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn parse_byte_record(record: &csv::ByteRecord, timestamps: TimestampFormat) -> Row {
+    if record.len() != RECORD_LENGTH {
+        return Err(ReadError::FieldCountError {
+            expected: RECORD_LENGTH,
+            found: record.len(),
+        });
+    }
+
+    let record: Record = record.deserialize(None)?;
+    parse_position(record, None, timestamps)
+}
+
+fn parse_mapped_record(
+    record: &csv::ByteRecord,
+    indices: &[usize; RECORD_LENGTH],
+    accuracy_index: Option<usize>,
+    header_length: usize,
+    timestamps: TimestampFormat,
+) -> Row {
+    if record.len() != header_length {
+        return Err(ReadError::FieldCountError {
+            expected: header_length,
+            found: record.len(),
+        });
+    }
+
+    let picked: csv::ByteRecord = indices.iter().map(|&index| &record[index]).collect();
+    let accuracy = match accuracy_index {
+        Some(index) => {
+            let (accuracy,): (Option<f64>,) =
+                csv::ByteRecord::from(vec![&record[index]]).deserialize(None)?;
+            accuracy
+        }
+        None => None,
+    };
+    let record: Record = picked.deserialize(None)?;
+    parse_position(record, accuracy, timestamps)
+}
+
+fn parse_position(record: Record, accuracy: Option<f64>, timestamps: TimestampFormat) -> Row {
+    let (id, datetime, location) = parse_record(record, timestamps)?;
+    let id = id.ok_or_else(|| ReadError::MissingValueError {
+        field: "id".to_string(),
+    })?;
+
+    Ok((id, Position::new(datetime, location, accuracy)?))
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    id_ride: Option<u32>,
+    lat: Option<f64>,
+    lng: Option<f64>,
+    timestamp: Option<JsonTimestamp>,
+    accuracy: Option<f64>,
+}
+
+// A whole ride as one JSON object, its positions like JSON Lines rows
+// without the id, e.g.
+// `{"id_ride":1,"positions":[{"lat":37.96,"lng":23.72,"timestamp":1405594957}]}`.
+#[derive(Deserialize)]
+struct JsonRide {
+    id_ride: u32,
+    positions: Vec<JsonPosition>,
+}
+
+#[derive(Deserialize)]
+struct JsonPosition {
+    lat: Option<f64>,
+    lng: Option<f64>,
+    timestamp: Option<JsonTimestamp>,
+    accuracy: Option<f64>,
+}
+
+// The whole ride is rejected if any of its positions is, naming the first
+// bad position by its index.
+pub(super) fn parse_json_ride(
+    content: &[u8],
+    timestamps: TimestampFormat,
+    tariff: &Tariff,
+) -> Result<Ride, Rejection> {
+    let ride: JsonRide =
+        serde_json::from_slice(content).map_err(|err| Rejection::from(&ReadError::from(err)))?;
+
+    let mut positions = Vec::with_capacity(ride.positions.len());
+    for (index, position) in ride.positions.into_iter().enumerate() {
+        let timestamp = position.timestamp.map(String::from);
+        let record = (Some(ride.id_ride), position.lat, position.lng, timestamp);
+        let parsed =
+            parse_position(record, position.accuracy, timestamps).and_then(|(_, position)| {
+                tariff.check_location(&position.location)?;
+                Ok(position)
+            });
+        match parsed {
+            Ok(position) => positions.push(position),
+            Err(err) => {
+                let rejection = Rejection::from(&err);
+                return Err(Rejection {
+                    reason: format!("position {}: {}", index, rejection.reason),
+                    ..rejection
+                });
+            }
+        }
+    }
+
+    Ok(Ride::new(ride.id_ride, positions))
+}
+
+// Timestamps may be JSON numbers or strings, and are parsed from their text
+// either way.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonTimestamp {
+    Number(serde_json::Number),
+    Text(String),
+}
+
+impl From<JsonTimestamp> for String {
+    fn from(timestamp: JsonTimestamp) -> Self {
+        match timestamp {
+            JsonTimestamp::Number(number) => number.to_string(),
+            JsonTimestamp::Text(text) => text,
+        }
+    }
+}
+
+pub(super) struct JsonLinesPositions<R: io::Read> {
+    reader: BufReader<R>,
+    timestamps: TimestampFormat,
+    // Where the line in `buffer` starts.
+    line: u64,
+    byte: u64,
+    buffer: Vec<u8>,
+}
+
+impl<R: io::Read> JsonLinesPositions<R> {
+    pub(super) fn new(input: R, timestamps: TimestampFormat) -> Self {
+        JsonLinesPositions {
+            reader: BufReader::new(input),
+            timestamps,
+            line: 0,
+            byte: 0,
+            buffer: vec![],
+        }
+    }
+}
+
+impl<R: io::Read> PositionReader for JsonLinesPositions<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, ReadError> {
+        loop {
+            self.line += 1;
+            self.byte += self.buffer.len() as u64;
+            self.buffer.clear();
+            if self.reader.read_until(b'\n', &mut self.buffer)? == 0 {
+                return Ok(None);
+            }
+
+            let text = trim_line(&self.buffer);
+            if text.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let row = serde_json::from_slice(text)
+                .map_err(ReadError::from)
+                .and_then(|record: JsonRecord| {
+                    let timestamp = record.timestamp.map(String::from);
+                    parse_position(
+                        (record.id_ride, record.lat, record.lng, timestamp),
+                        record.accuracy,
+                        self.timestamps,
+                    )
+                });
+
+            return Ok(Some(row.map_err(|err| self.row_error(err))));
+        }
+    }
+
+    fn row_error(&self, error: ReadError) -> ReadError {
+        ReadError::RowError {
+            line: self.line,
+            byte: self.byte,
+            raw: String::from_utf8_lossy(trim_line(&self.buffer)).into_owned(),
+            error: Box::new(error),
+        }
+    }
+}
+
+// Rejects positions that `tariff` doesn't accept, as rows of `reader`.
+pub(super) struct CheckedPositions<R: PositionReader> {
+    reader: R,
+    tariff: Arc<Tariff>,
+}
+
+impl<R: PositionReader> CheckedPositions<R> {
+    pub(super) fn new(reader: R, tariff: Arc<Tariff>) -> Self {
+        CheckedPositions { reader, tariff }
+    }
+}
+
+impl<R: PositionReader> PositionReader for CheckedPositions<R> {
+    fn next_row(&mut self) -> Result<Option<Row>, ReadError> {
+        let row = match self.reader.next_row()? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        Ok(Some(row.and_then(
+            |(id, position)| match self.tariff.check_location(&position.location) {
+                Ok(()) => Ok((id, position)),
+                Err(err) => Err(self.reader.row_error(err)),
+            },
+        )))
+    }
+
+    fn row_error(&self, error: ReadError) -> ReadError {
+        self.reader.row_error(error)
+    }
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut reader: impl PositionReader) -> Vec<Row> {
+        let mut rows = vec![];
+        while let Some(row) = reader.next_row().unwrap() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn read_csv(input: &str, format: CsvInput) -> Result<Vec<Row>, ReadError> {
+        let mut reader = CsvPositions::new(input.as_bytes(), format, TimestampFormat::default());
+        let mut rows = vec![];
+        while let Some(row) = reader.next_row()? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    #[test]
+    fn it_reports_rejected_rows_as_written() {
+        let input = "driver;timestamp;lng;lat;id_ride\r\n\
+                     \"Doe; \"\"Jo\"\"\";1405594957;23.72;north;1\r\n\
+                     7;1405594966;23.73;37.97\r\n\
+                     7;1405594975;23.74;37.98;1";
+        let format = CsvInput {
+            delimiter: b';',
+            columns: Some(CsvColumns::default()),
+        };
+
+        let raws: Vec<_> = read_csv(input, format)
+            .unwrap()
+            .into_iter()
+            .map(|row| match row {
+                Err(ReadError::RowError { byte, raw, .. }) => (byte, raw),
+                Ok(_) => (0, "ok".to_string()),
+                Err(err) => panic!("expected a row error, got {:?}", err),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    34,
+                    "\"Doe; \"\"Jo\"\"\";1405594957;23.72;north;1".to_string()
+                ),
+                (74, "7;1405594966;23.73;37.97".to_string()),
+                (0, "ok".to_string()),
+            ],
+            raws
+        );
+    }
+
+    #[test]
+    fn it_reports_rejected_rows_deep_into_the_input() {
+        let good = "1,37.96,23.72,1405594957\n".repeat(10_000);
+        let input = format!("{}1,37.96,23.72,\"soon\"\n{}", good, good);
+
+        let rows = read_csv(&input, CsvInput::default()).unwrap();
+        match &rows[10_000] {
+            Err(ReadError::RowError {
+                line, byte, raw, ..
+            }) => {
+                assert_eq!((10_001, good.len() as u64), (*line, *byte));
+                assert_eq!("1,37.96,23.72,\"soon\"", raw);
+            }
+            other => panic!("expected a row error, got {:?}", other),
+        }
+        assert_eq!(1, rows.iter().filter(|row| row.is_err()).count());
+    }
+
+    #[test]
+    fn it_maps_csv_columns_by_name() {
+        let input = "driver;timestamp;lng;accuracy;lat;id_ride\n\
+                     7;1405594957;23.72;5;37.96;1\n\
+                     7;1405594966;23.73;4;37.97;2\n";
+        let format = CsvInput {
+            delimiter: b';',
+            columns: Some(CsvColumns::default()),
+        };
+
+        let rows: Vec<_> = read_csv(input, format)
+            .unwrap()
+            .into_iter()
+            .map(|row| {
+                let (id, position) = row.unwrap();
+                (
+                    id,
+                    position.location.latitude,
+                    position.location.longitude,
+                    position.datetime.timestamp(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![(1, 37.96, 23.72, 1405594957), (2, 37.97, 23.73, 1405594966)],
+            rows
+        );
+    }
+
+    #[test]
+    fn it_reports_bad_mapped_csv_rows() {
+        let input = "ride,latitude,longitude,time,speed\n\
+                     1,37.96,23.72,1405594957,12\n\
+                     1,37.96,23.72,1405594966\n\
+                     1,,23.72,1405594970,13\n";
+        let format = CsvInput {
+            columns: Some(CsvColumns {
+                id: "ride".to_string(),
+                latitude: "latitude".to_string(),
+                longitude: "longitude".to_string(),
+                timestamp: "time".to_string(),
+                accuracy: None,
+            }),
+            ..CsvInput::default()
+        };
+
+        let rows = read_csv(input, format).unwrap();
+
+        assert_eq!(3, rows.len());
+        assert!(rows[0].is_ok());
+        let errors: Vec<_> = rows[1..]
+            .iter()
+            .map(|row| match row {
+                Err(ReadError::RowError {
+                    line, raw, error, ..
+                }) => (*line, raw.as_str(), error.to_string()),
+                _ => panic!("expected a row error"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    3,
+                    "1,37.96,23.72,1405594966",
+                    "expected 5 fields, found 4".to_string()
+                ),
+                (
+                    4,
+                    "1,,23.72,1405594970,13",
+                    "missing value for latitude".to_string()
+                ),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn it_reads_accuracies() {
+        let input = "id_ride,lat,lng,timestamp,hdop_m\n\
+                     1,37.96,23.72,1405594957,4.5\n\
+                     1,37.96,23.72,1405594966,\n\
+                     1,37.96,23.72,1405594970,-3\n";
+        let format = CsvInput {
+            columns: Some(CsvColumns {
+                accuracy: Some("hdop_m".to_string()),
+                ..CsvColumns::default()
+            }),
+            ..CsvInput::default()
+        };
+
+        let accuracies: Vec<_> = read_csv(input, format)
+            .unwrap()
+            .into_iter()
+            .map(|row| match row {
+                Ok((_, position)) => Ok(position.accuracy),
+                Err(err) => Err(err.kind()),
+            })
+            .collect();
+        assert_eq!(
+            vec![Ok(Some(4.5)), Ok(None), Err("invalid_accuracy")],
+            accuracies
+        );
+
+        let input =
+            "{\"id_ride\":1,\"lat\":37.96,\"lng\":23.72,\"timestamp\":1405594957,\"accuracy\":5}\n";
+        let rows = read_all(JsonLinesPositions::new(
+            input.as_bytes(),
+            TimestampFormat::default(),
+        ));
+        assert_eq!(Some(5.0), rows[0].as_ref().unwrap().1.accuracy);
+    }
+
+    #[test]
+    fn it_reads_json_rides() {
+        let tariff = Tariff::default();
+        let parse =
+            |input: &str| parse_json_ride(input.as_bytes(), TimestampFormat::default(), &tariff);
+
+        let ride = parse(
+            r#"{"id_ride": 3, "positions": [
+                {"lat": 37.96, "lng": 23.72, "timestamp": 1405594957},
+                {"lat": 37.97, "lng": 23.73, "timestamp": "2014-07-17T11:02:46Z", "accuracy": 5}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(3, ride.id());
+        assert_eq!(2, ride.positions().len());
+        assert_eq!(Some(5.0), ride.positions()[1].accuracy());
+        assert_eq!(1405594966, ride.positions()[1].datetime().timestamp());
+
+        let rejection = parse(
+            r#"{"id_ride": 3, "positions": [
+                {"lat": 37.96, "lng": 23.72, "timestamp": 1405594957},
+                {"lat": 0, "lng": 0, "timestamp": 1405594966}
+            ]}"#,
+        )
+        .unwrap_err();
+        assert_eq!("null_island", rejection.kind);
+        assert!(rejection.reason.starts_with("position 1: "));
+
+        assert_eq!("json", parse(r#"{"positions": []}"#).unwrap_err().kind);
+    }
+
+    #[test]
+    fn it_requires_mapped_columns() {
+        let format = CsvInput {
+            columns: Some(CsvColumns::default()),
+            ..CsvInput::default()
+        };
+
+        match read_csv(
+            "id_ride,lat,lon,timestamp\n1,37.96,23.72,1405594957\n",
+            format.clone(),
+        ) {
+            Err(ReadError::MissingColumnError { column }) => assert_eq!("lng", column),
+            other => panic!("expected a missing column, got {:?}", other.err()),
+        }
+        assert!(read_csv("", format).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_reads_json_lines() {
+        let input = "{\"id_ride\":1,\"lat\":37.96,\"lng\":23.72,\"timestamp\":1405594957}\n\
+                     \n\
+                     {\"timestamp\":\"2014-07-17T11:02:46Z\",\"lng\":23.73,\"lat\":37.97,\"id_ride\":2,\"accuracy\":5}\r\n";
+        let rows = read_all(JsonLinesPositions::new(
+            input.as_bytes(),
+            TimestampFormat::default(),
+        ));
+
+        let rows: Vec<_> = rows
+            .into_iter()
+            .map(|row| {
+                let (id, position) = row.unwrap();
+                (
+                    id,
+                    position.location.latitude,
+                    position.datetime.timestamp(),
+                )
+            })
+            .collect();
+        assert_eq!(vec![(1, 37.96, 1405594957), (2, 37.97, 1405594966)], rows);
+    }
+
+    #[test]
+    fn it_reports_bad_json_lines() {
+        let input = "{\"id_ride\":1,\"lat\":37.96,\"lng\":23.72,\"timestamp\":1405594957}\n\
+                     {\"id_ride\":1,\"lat\":\"north\",\"lng\":23.72,\"timestamp\":1405594966}\n\
+                     {\"id_ride\":null,\"lat\":37.96,\"lng\":23.72,\"timestamp\":1405594970}\n\
+                     {\"id_ride\":1,\"lat\":37.96,\"lng\":23.72}\n\
+                     not json\n";
+        let rows = read_all(JsonLinesPositions::new(
+            input.as_bytes(),
+            TimestampFormat::default(),
+        ));
+
+        assert_eq!(5, rows.len());
+        assert!(rows[0].is_ok());
+        let errors: Vec<_> = rows[1..]
+            .iter()
+            .map(|row| match row {
+                Err(ReadError::RowError {
+                    line,
+                    byte,
+                    raw,
+                    error,
+                }) => (*line, *byte, raw.len(), error.kind()),
+                _ => panic!("expected a row error"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (2, 61, 62, "json"),
+                (3, 124, 63, "missing_value"),
+                (4, 188, 37, "missing_value"),
+                (5, 226, 8, "json"),
+            ],
+            errors
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use super::audit::Classification;
+use super::filters::{Candidate, SegmentFilter};
+use super::geodesy::RideDistance;
+use super::smoothing::KalmanFilter;
+use super::{Amount, FareBreakdown, Position, RoundingPoint, Segment, Tariff};
+
+// Prices a ride as its positions arrive, e.g. for a taximeter, by the same
+// rules as a whole ride. Each position takes constant time and memory. The
+// last position's segment is only decided once the next one arrives, as
+// some filters look one position ahead; until then, `fare` and `breakdown`
+// count it as if the ride ended there.
+pub struct FareMeter<'a> {
+    tariff: &'a Tariff,
+    segments: Segmenter<'a>,
+    totals: Totals,
+}
+
+impl<'a> FareMeter<'a> {
+    pub fn new(tariff: &'a Tariff) -> Self {
+        FareMeter {
+            tariff,
+            segments: Segmenter::new(tariff),
+            totals: Totals::new(tariff),
+        }
+    }
+
+    pub fn push(&mut self, position: &Position) {
+        if let Some((segment, discarded)) = self.segments.push(position) {
+            self.totals.add(self.tariff, &segment, discarded);
+        }
+    }
+
+    // The fare if the ride ended now.
+    pub fn fare(&self) -> Amount {
+        self.price().0
+    }
+
+    pub fn breakdown(&self) -> FareBreakdown {
+        self.price().1
+    }
+
+    fn price(&self) -> (Amount, FareBreakdown) {
+        match self.segments.peek() {
+            Some((segment, discarded)) => {
+                let mut totals = self.totals.clone();
+                totals.add(self.tariff, &segment, discarded);
+                totals.price(self.tariff)
+            }
+            None => self.totals.price(self.tariff),
+        }
+    }
+}
+
+// Splits positions into segments as they arrive, smoothing them and running
+// the tariff's filters over them. A segment runs from the last kept position
+// to the latest one, and is decided once the position after it arrives.
+struct Segmenter<'a> {
+    tariff: &'a Tariff,
+    smoothing: Option<KalmanFilter>,
+    filters: Vec<Box<dyn SegmentFilter>>,
+    distance: RideDistance,
+    kept: Option<Position>,
+    previous_segment: Option<Segment>,
+    pending: Option<Position>,
+}
+
+// A segment, and how the tariff's filters discarded it, if they did.
+pub(super) type Considered = (Segment, Option<Classification>);
+
+impl<'a> Segmenter<'a> {
+    fn new(tariff: &'a Tariff) -> Self {
+        Segmenter {
+            tariff,
+            smoothing: tariff.smoothing.map(KalmanFilter::new),
+            filters: tariff.filters.iter().map(|filter| filter.build()).collect(),
+            distance: tariff.distance.for_ride(tariff.flat_earth),
+            kept: None,
+            previous_segment: None,
+            pending: None,
+        }
+    }
+
+    // The segment ending at the position before this one, now that it can be
+    // decided.
+    fn push(&mut self, position: &Position) -> Option<Considered> {
+        let position = match &mut self.smoothing {
+            Some(filter) => filter.next(position),
+            None => position.clone(),
+        };
+        let decided = match self.pending.take() {
+            Some(pending) => self.decide(pending, Some(&position)),
+            None => None,
+        };
+        self.pending = Some(position);
+        decided
+    }
+
+    // The segment ending at the latest position, as if the ride ended there,
+    // without deciding it.
+    fn peek(&self) -> Option<Considered> {
+        let pending = self.pending.as_ref()?;
+        self.consider(&mut self.distance.clone(), pending, None)
+    }
+
+    fn decide(&mut self, end: Position, next: Option<&Position>) -> Option<Considered> {
+        let mut distance = self.distance.clone();
+        let considered = self.consider(&mut distance, &end, next);
+        self.distance = distance;
+
+        match &considered {
+            Some((_, Some(_))) => {}
+            Some((segment, None)) => {
+                self.kept = Some(end);
+                self.previous_segment = Some(segment.clone());
+            }
+            None => self.kept = Some(end),
+        }
+        considered
+    }
+
+    fn consider(
+        &self,
+        distance: &mut RideDistance,
+        end: &Position,
+        next: Option<&Position>,
+    ) -> Option<Considered> {
+        let start = self.kept.as_ref()?;
+        let segment = Segment {
+            start: start.datetime,
+            end: end.datetime,
+            distance_km: distance.km(&start.location, &end.location),
+        };
+
+        let candidate = Candidate {
+            tariff: self.tariff,
+            distance,
+            start,
+            end,
+            next,
+            segment: &segment,
+            previous: self.previous_segment.as_ref(),
+        };
+        let discarded = self
+            .filters
+            .iter()
+            .find(|filter| !filter.keep(&candidate))
+            .map(|filter| filter.discarded());
+        Some((segment, discarded))
+    }
+}
+
+// What the segments kept so far add up to, in micros before rounding.
+#[derive(Clone)]
+struct Totals {
+    day: i64,
+    night: i64,
+    idle: i64,
+    breakdown: FareBreakdown,
+}
+
+impl Totals {
+    fn new(tariff: &Tariff) -> Self {
+        Totals {
+            day: 0,
+            night: 0,
+            idle: 0,
+            breakdown: FareBreakdown::new(tariff.currency),
+        }
+    }
+
+    fn add(&mut self, tariff: &Tariff, segment: &Segment, discarded: Option<Classification>) {
+        if discarded.is_some() {
+            self.breakdown.discarded_segments += 1;
+            return;
+        }
+
+        let charge = segment.get_charge(tariff);
+        let round = |micros| match tariff.rounding_point {
+            RoundingPoint::Segment => tariff.rounding.round(micros, tariff.currency),
+            RoundingPoint::Total => micros,
+        };
+
+        self.breakdown.day_km += charge.day_km;
+        self.breakdown.night_km += charge.night_km;
+        self.breakdown.idle_seconds += charge.idle_seconds;
+        self.day += round(charge.day_fare);
+        self.night += round(charge.night_fare);
+        self.idle += round(charge.idle_fare);
+    }
+
+    fn price(&self, tariff: &Tariff) -> (Amount, FareBreakdown) {
+        let round = |micros| tariff.rounding.round(micros, tariff.currency);
+        let to_amount = |micros| Amount::from_micros(round(micros), tariff.currency);
+
+        let flag = tariff.standard_flag.micros();
+        let charges = self.day + self.night + self.idle;
+        // Rounded per segment, every charge is already rounded, so the
+        // breakdown adds up to the fare.
+        let fare = match tariff.rounding_point {
+            RoundingPoint::Segment => round(flag) + charges,
+            RoundingPoint::Total => round(flag + charges),
+        };
+        let minimum_fare = round(tariff.minimum_fare.micros());
+        let breakdown = FareBreakdown {
+            flag: to_amount(flag),
+            day_amount: to_amount(self.day),
+            night_amount: to_amount(self.night),
+            idle_amount: to_amount(self.idle),
+            minimum_applied: minimum_fare > fare,
+            ..self.breakdown.clone()
+        };
+
+        (
+            Amount::from_micros(fare.max(minimum_fare), tariff.currency),
+            breakdown,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{haversine, Filter, Ride, Rounding, Smoothing};
+    use super::*;
+    use chrono::prelude::*;
+
+    // The rides of paths.csv.
+    fn rides() -> Vec<Ride> {
+        let mut rides: Vec<Ride> = vec![];
+        for row in std::fs::read_to_string("paths.csv").unwrap().lines() {
+            let fields: Vec<&str> = row.split(',').collect();
+            let id = fields[0].parse().unwrap();
+            let position = Position {
+                datetime: Utc.timestamp(fields[3].parse().unwrap(), 0),
+                location: haversine::Location {
+                    latitude: fields[1].parse().unwrap(),
+                    longitude: fields[2].parse().unwrap(),
+                },
+                accuracy: None,
+            };
+            match rides.last_mut() {
+                Some(ride) if ride.id == id => ride.positions.push(position),
+                _ => rides.push(Ride {
+                    id,
+                    positions: vec![position],
+                }),
+            }
+        }
+        rides
+    }
+
+    // Smooths and filters positions, and rounds each charge to cash.
+    fn cleaning_tariff() -> Tariff {
+        Tariff {
+            smoothing: Some(Smoothing::default()),
+            filters: vec![
+                Filter::MaxSpeed,
+                Filter::MinDisplacement { min_distance: 5.0 },
+                Filter::Spike {
+                    max_turn: 150.0,
+                    min_leg: 20.0,
+                },
+            ],
+            rounding: Rounding::Cash,
+            rounding_point: RoundingPoint::Segment,
+            ..Tariff::default()
+        }
+    }
+
+    #[test]
+    fn it_prices_rides_as_they_go() {
+        let tariffs = [Tariff::default(), cleaning_tariff()];
+
+        for tariff in &tariffs {
+            for ride in rides() {
+                let mut meter = FareMeter::new(tariff);
+                for (index, position) in ride.positions.iter().enumerate() {
+                    meter.push(position);
+
+                    // Every ten positions, as if the ride ended there.
+                    if index % 10 == 0 {
+                        let so_far = Ride {
+                            id: ride.id,
+                            positions: ride.positions[..=index].to_vec(),
+                        };
+                        let want = so_far.calculate_fare(tariff);
+                        assert_eq!(want.amount, meter.fare(), "ride {}", ride.id);
+                    }
+                }
+
+                let want = ride.calculate_fare(tariff);
+                assert_eq!(want.amount, meter.fare(), "ride {}", ride.id);
+                assert_eq!(want.breakdown, meter.breakdown(), "ride {}", ride.id);
+            }
+        }
+    }
+
+    #[test]
+    fn it_prices_paths_with_a_cleaning_tariff() {
+        // Each ride's fare in cents and the segments discarded from it.
+        let want = [
+            (1, 1190, 19),
+            (2, 1365, 68),
+            (3, 3480, 162),
+            (4, 345, 6),
+            (5, 2280, 33),
+            (6, 775, 53),
+            (7, 3090, 159),
+            (8, 940, 13),
+            (9, 630, 46),
+        ];
+
+        let tariff = cleaning_tariff();
+        for (ride, (id, cents, discarded)) in rides().iter().zip(want) {
+            let mut meter = FareMeter::new(&tariff);
+            for position in &ride.positions {
+                meter.push(position);
+            }
+            let fare = ride.calculate_fare(&tariff);
+
+            assert_eq!(id, ride.id);
+            assert_eq!(cents, meter.fare().minor_units(), "ride {}", id);
+            assert_eq!(cents, fare.amount.minor_units(), "ride {}", id);
+            assert_eq!(
+                discarded,
+                meter.breakdown().discarded_segments,
+                "ride {}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn its_breakdown_adds_up_when_rounding_per_segment() {
+        for rounding in [Rounding::HalfUp, Rounding::HalfEven, Rounding::Cash] {
+            let tariff = Tariff {
+                rounding,
+                rounding_point: RoundingPoint::Segment,
+                ..Tariff::default()
+            };
+            for ride in rides() {
+                let mut meter = FareMeter::new(&tariff);
+                for position in &ride.positions {
+                    meter.push(position);
+                }
+
+                let breakdown = meter.breakdown();
+                if breakdown.minimum_applied {
+                    continue;
+                }
+                let parts = [
+                    breakdown.flag,
+                    breakdown.day_amount,
+                    breakdown.night_amount,
+                    breakdown.idle_amount,
+                ];
+                assert_eq!(
+                    meter.fare().minor_units(),
+                    parts.iter().map(Amount::minor_units).sum::<i64>(),
+                    "ride {} {:?}",
+                    ride.id,
+                    rounding
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_counts_the_latest_segment_until_the_next_fix() {
+        let tariff = Tariff::default();
+        let at = |seconds: i64, latitude| {
+            Position::new(
+                Utc.timestamp(1405594957 + seconds, 0),
+                haversine::Location {
+                    latitude,
+                    longitude: 23.72,
+                },
+                None,
+            )
+            .unwrap()
+        };
+        let mut meter = FareMeter::new(&tariff);
+
+        assert_eq!(347, meter.fare().minor_units());
+        assert!(meter.breakdown().minimum_applied);
+
+        meter.push(&at(0, 37.96));
+        meter.push(&at(600, 38.01));
+        // About 5.6 km at the day rate.
+        let breakdown = meter.breakdown();
+        assert!((breakdown.day_km - 5.56).abs() < 0.01);
+        assert_eq!(541, meter.fare().minor_units());
+
+        // Too fast, so it isn't priced, but the previous segment still is.
+        meter.push(&at(610, 38.11));
+        assert_eq!(1, meter.breakdown().discarded_segments);
+        assert_eq!(541, meter.fare().minor_units());
+    }
+}
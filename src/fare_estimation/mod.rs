@@ -1,26 +1,69 @@
+mod audit;
+mod breakdown;
+mod filters;
+mod geodesy;
+mod grouping;
 mod haversine;
+mod input;
+mod meter;
+mod money;
+mod normalization;
+mod output;
+mod rejection;
+mod server;
+mod smoothing;
+mod tariff;
+mod timestamp;
+
+pub use audit::{AuditSink, AuditedSegment, Classification, CsvAuditSink, JsonLinesAuditSink};
+pub use breakdown::FareBreakdown;
+pub use filters::Filter;
+pub use geodesy::Distance;
+pub use grouping::RideGrouping;
+pub use haversine::Location;
+pub use input::{CsvColumns, CsvInput, InputFormat};
+pub use meter::FareMeter;
+pub use money::{Amount, AmountFormat, Currency, Price, Rounding, RoundingPoint};
+pub use normalization::{NormalizationReport, TimestampConflict};
+pub use output::OutputFormat;
+pub use rejection::{CsvErrorSink, ErrorSink, Rejection};
+pub use server::{serve, ServerOptions};
+pub use smoothing::Smoothing;
+pub use tariff::{BoundingBox, Tariff, TariffError};
+pub use timestamp::TimestampFormat;
 
 use chrono::prelude::*;
 use chrono::{DateTime, Utc};
-use serde::{Serialize, Serializer};
-use std::convert::From;
+use filters::Candidate;
+use grouping::group_rides;
+use input::{CheckedPositions, CsvPositions, JsonLinesPositions};
+use meter::Considered;
+use output::FareWriter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::{From, TryFrom};
+use std::fmt;
 use std::io;
-use std::io::BufReader;
-use std::sync::mpsc;
-use std::thread;
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::task;
 
 const MAX_SPEED: f64 = 100.0;
 const IDLE_SPEED: f64 = 10.0;
-const FARE_PER_SECOND_IDLE: f64 = 11.90 / (60.0 * 60.0);
-const FARE_PER_KM_NIGHT: f64 = 1.30;
-const FARE_PER_KM_DAY: f64 = 0.74;
-const STANDARD_FLAG: f64 = 1.30;
-const MINIMUM_FARE: f64 = 3.47;
+const FARE_PER_HOUR_IDLE: Price = Price::from_micros(11_900_000);
+const FARE_PER_KM_NIGHT: Price = Price::from_micros(1_300_000);
+const FARE_PER_KM_DAY: Price = Price::from_micros(740_000);
+const STANDARD_FLAG: Price = Price::from_micros(1_300_000);
+const MINIMUM_FARE: Price = Price::from_micros(3_470_000);
 
 #[derive(Debug)]
 pub enum MainError {
     ReadError(ReadError),
     IOError(io::Error),
+    TariffError(TariffError),
+    HTTPError(hyper::Error),
+    // An `Options` field that can't be used, and why.
+    OptionError(&'static str, &'static str),
 }
 
 impl From<io::Error> for MainError {
@@ -35,145 +78,551 @@ impl From<ReadError> for MainError {
     }
 }
 
+impl From<TariffError> for MainError {
+    fn from(error: TariffError) -> Self {
+        MainError::TariffError(error)
+    }
+}
+
+impl From<hyper::Error> for MainError {
+    fn from(error: hyper::Error) -> Self {
+        MainError::HTTPError(error)
+    }
+}
+
+// Counts for a whole `estimate_fare` run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub rides: usize,
+    pub rejected_rows: usize,
+    pub normalized: NormalizationReport,
+}
+
+// Positions are read as `input_format`, with timestamps as `timestamp_format`,
+// and put together into rides by `grouping`; grouping by id writes fares in
+// order of ride id rather than input order. With `normalize`, each ride's
+// positions are ordered by timestamp and left with one per timestamp, exact
+// duplicates dropped and other conflicts resolved as it says; what changed is
+// counted in the `Summary`. Fares are written as `output_format`, with amounts
+// as `amount_format`. Rejected rows are dropped unless an `errors` sink is
+// given. Fares are calculated in parallel either way; `preserve_order` only
+// decides whether they're written in input order or as soon as they're ready.
+// With `breakdown`, each fare is written with how it was made up, under a
+// header. With an `audit` sink, every segment considered is recorded there
+// too, ride by ride in the same order as the fares.
+//
+// Memory is bounded by `channel_capacity`, the number of rides read ahead of
+// the calculations, and `max_in_flight`, the number of rides being priced or
+// waiting to be written. Both must be at least 1.
+pub struct Options {
+    pub tariff: Tariff,
+    pub input_format: InputFormat,
+    pub timestamp_format: TimestampFormat,
+    pub grouping: RideGrouping,
+    pub normalize: Option<TimestampConflict>,
+    pub output_format: OutputFormat,
+    pub amount_format: AmountFormat,
+    pub errors: Box<dyn ErrorSink>,
+    pub preserve_order: bool,
+    pub breakdown: bool,
+    pub audit: Option<Box<dyn AuditSink>>,
+    pub channel_capacity: usize,
+    pub max_in_flight: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            tariff: Tariff::default(),
+            input_format: InputFormat::default(),
+            timestamp_format: TimestampFormat::default(),
+            grouping: RideGrouping::default(),
+            normalize: None,
+            output_format: OutputFormat::default(),
+            amount_format: AmountFormat::default(),
+            errors: Box::new(|_| {}),
+            preserve_order: true,
+            breakdown: false,
+            audit: None,
+            channel_capacity: 1024,
+            max_in_flight: 256,
+        }
+    }
+}
+
 pub async fn estimate_fare(
-    input: (impl io::Read + Send + 'static),
-    output: (impl io::Write + Send + 'static),
-) -> Result<(), MainError> {
-    let (parsed_records_tx, parsed_records_rx) = mpsc::channel();
-    thread::spawn(move || {
-        read_csv(input, parsed_records_tx);
+    input: impl io::Read + Send + 'static,
+    output: impl io::Write + Send + 'static,
+    options: Options,
+) -> Result<Summary, MainError> {
+    let Options {
+        tariff,
+        input_format,
+        timestamp_format,
+        grouping,
+        normalize,
+        output_format,
+        amount_format,
+        errors,
+        preserve_order,
+        breakdown,
+        audit,
+        channel_capacity,
+        max_in_flight,
+    } = options;
+    if channel_capacity == 0 {
+        return Err(MainError::OptionError(
+            "channel_capacity",
+            "must be at least 1",
+        ));
+    }
+    if max_in_flight == 0 {
+        return Err(MainError::OptionError(
+            "max_in_flight",
+            "must be at least 1",
+        ));
+    }
+    tariff.validate()?;
+    let tariff = Arc::new(tariff);
+
+    // Reading and writing block on their input and output, so they are kept
+    // off the runtime's threads, leaving those to the pricing.
+    let (parsed_records_tx, parsed_records_rx) = mpsc::channel(channel_capacity);
+    let reading_tariff = tariff.clone();
+    let reading = task::spawn_blocking(move || {
+        read_rides(
+            input,
+            input_format,
+            timestamp_format,
+            grouping,
+            reading_tariff,
+            parsed_records_tx,
+        )
     });
 
-    let (fares_tx, fares_rx) = mpsc::channel();
-    tokio::spawn(async move {
-        calculate_all_fares(parsed_records_rx, fares_tx).await;
+    let (fares_tx, fares_rx) = mpsc::channel(channel_capacity);
+    let audited = audit.is_some();
+    let calculation = tokio::spawn(async move {
+        calculate_all_fares(
+            parsed_records_rx,
+            fares_tx,
+            tariff,
+            errors,
+            normalize,
+            max_in_flight,
+            audited,
+        )
+        .await
     });
 
-    write_csv(output, fares_rx).unwrap();
+    let output = FareWriter::new(output, output_format, amount_format, breakdown);
+    task::spawn_blocking(move || write_fares(output, fares_rx, preserve_order, audit))
+        .await
+        .unwrap()?;
+    reading.await.unwrap()?;
 
-    Ok(())
+    Ok(calculation.await.unwrap()?)
 }
 
-#[derive(Clone, Debug)]
-struct Position {
+// `accuracy` is the radius in metres the fix is likely within, if known.
+// Deserializing checks a position as `Position::new` does.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedPosition")]
+pub struct Position {
     datetime: DateTime<Utc>,
     location: haversine::Location,
+    accuracy: Option<f64>,
 }
 
-struct Segment {
+impl Position {
+    // Fails unless `location` is a valid latitude and longitude and
+    // `accuracy`, if given, a finite number of metres.
+    pub fn new(
+        datetime: DateTime<Utc>,
+        location: Location,
+        accuracy: Option<f64>,
+    ) -> Result<Self, ReadError> {
+        check_coordinates(&location)?;
+        if let Some(value) = accuracy {
+            if !value.is_finite() || value < 0.0 {
+                return Err(ReadError::InvalidAccuracyError { value });
+            }
+        }
+
+        Ok(Position {
+            datetime,
+            location,
+            accuracy,
+        })
+    }
+
+    pub fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn accuracy(&self) -> Option<f64> {
+        self.accuracy
+    }
+}
+
+#[derive(Deserialize)]
+struct UncheckedPosition {
+    datetime: DateTime<Utc>,
+    location: Location,
+    accuracy: Option<f64>,
+}
+
+impl TryFrom<UncheckedPosition> for Position {
+    type Error = ReadError;
+
+    fn try_from(position: UncheckedPosition) -> Result<Self, Self::Error> {
+        Position::new(position.datetime, position.location, position.accuracy)
+    }
+}
+
+// The stretch of a ride between two positions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
     start: DateTime<Utc>,
     end: DateTime<Utc>,
     distance_km: f64,
 }
 
 impl Segment {
-    fn speed(&self) -> f64 {
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>, distance_km: f64) -> Self {
+        Segment {
+            start,
+            end,
+            distance_km,
+        }
+    }
+
+    pub fn start(&self) -> DateTime<Utc> {
+        self.start
+    }
+
+    pub fn end(&self) -> DateTime<Utc> {
+        self.end
+    }
+
+    pub fn distance_km(&self) -> f64 {
+        self.distance_km
+    }
+
+    // In km/h.
+    pub fn speed(&self) -> f64 {
         if self.distance_km == 0.0 {
             return 0.0;
         }
         let dt = self.duration_seconds();
-        if dt == 0 {
+        if dt == 0.0 {
             return f64::INFINITY;
         }
 
-        let hours = dt as f64 / 3600.0;
+        let hours = dt / 3600.0;
         self.distance_km / hours
     }
 
-    fn duration_seconds(&self) -> i64 {
-        self.end.timestamp() - self.start.timestamp()
+    pub fn duration_seconds(&self) -> f64 {
+        seconds_between(&self.start, &self.end)
     }
 
-    fn get_fare(&self) -> f64 {
-        if self.is_idle() {
-            FARE_PER_SECOND_IDLE * self.duration_seconds() as f64
-        } else if self.is_day() {
-            FARE_PER_KM_DAY * self.distance_km
-        } else {
-            FARE_PER_KM_NIGHT * self.distance_km
-        }
+    // What the segment costs at the tariff's rates, before rounding, whether
+    // or not the tariff's filters would keep it.
+    pub fn get_fare(&self, tariff: &Tariff) -> Price {
+        Price::from_micros(self.get_charge(tariff).total())
     }
 
-    fn is_idle(&self) -> bool {
-        self.speed() <= IDLE_SPEED
+    fn get_charge(&self, tariff: &Tariff) -> Charge {
+        let duration = self.duration_seconds();
+        if self.is_idle(tariff) {
+            return Charge {
+                idle_seconds: duration,
+                idle_fare: tariff.fare_per_hour_idle.times(duration / 3600.0),
+                ..Charge::default()
+            };
+        }
+
+        let mut charge = Charge::default();
+        for piece in self.split(tariff) {
+            if piece.is_day(tariff) {
+                charge.day_km += piece.distance_km;
+                charge.day_fare += tariff.fare_per_km_day.times(piece.distance_km);
+            } else {
+                charge.night_km += piece.distance_km;
+                charge.night_fare += tariff.fare_per_km_night.times(piece.distance_km);
+            }
+        }
+        charge
     }
 
-    fn is_day(&self) -> bool {
-        if self.start.num_seconds_from_midnight() == 0 {
-            return true;
+    // Splits the segment wherever the rate changes, sharing the distance
+    // between pieces in proportion to their duration.
+    fn split(&self, tariff: &Tariff) -> Vec<Segment> {
+        let boundaries = tariff.rate_boundaries(&self.start, &self.end);
+        if boundaries.is_empty() {
+            return vec![Segment { ..*self }];
         }
 
-        // how to declare that only once?
-        let start_of_day = Utc
-            .ymd(1, 1, 1)
-            .and_hms(5, 0, 1)
-            .num_seconds_from_midnight();
+        let duration = self.duration_seconds();
+        let mut start = self.start;
+
+        boundaries
+            .into_iter()
+            .chain(std::iter::once(self.end))
+            .map(|end| {
+                let piece = Segment {
+                    start,
+                    end,
+                    distance_km: self.distance_km * seconds_between(&start, &end) / duration,
+                };
+                start = end;
+                piece
+            })
+            .collect()
+    }
 
-        self.start.num_seconds_from_midnight() >= start_of_day
+    fn is_idle(&self, tariff: &Tariff) -> bool {
+        self.speed() <= tariff.idle_speed
+    }
+
+    fn is_day(&self, tariff: &Tariff) -> bool {
+        tariff.is_day(&self.start)
+    }
+}
+
+fn seconds_between(start: &DateTime<Utc>, end: &DateTime<Utc>) -> f64 {
+    let duration = *end - *start;
+    match duration.num_microseconds() {
+        Some(micros) => micros as f64 / 1e6,
+        None => duration.num_milliseconds() as f64 / 1e3,
+    }
+}
+
+// What a segment costs at each rate, before rounding.
+#[derive(Clone, Debug, Default, PartialEq)]
+// Fares in micros.
+struct Charge {
+    day_km: f64,
+    day_fare: i64,
+    night_km: f64,
+    night_fare: i64,
+    idle_seconds: f64,
+    idle_fare: i64,
+}
+
+impl Charge {
+    fn total(&self) -> i64 {
+        self.day_fare + self.night_fare + self.idle_fare
     }
 }
 
-fn is_too_fast(speed: f64) -> bool {
-    speed > MAX_SPEED
+fn is_too_fast(speed: f64, tariff: &Tariff) -> bool {
+    speed > tariff.max_speed
 }
 
-#[derive(Clone)]
-struct Ride {
+// A ride's positions, in the order they were recorded. Rides are priced by
+// a tariff that is expected to be valid; see `Tariff::validate`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ride {
     id: u32,
     positions: Vec<Position>,
 }
 
+// A priced ride, holding on to its in-flight slot until it's written.
+// `segments` is only filled in when auditing.
+struct CalculatedFare {
+    sequence: usize,
+    fare: Fare,
+    segments: Vec<AuditedSegment>,
+    _slot: OwnedSemaphorePermit,
+}
+
 async fn calculate_all_fares(
-    rides: mpsc::Receiver<Result<Ride, ReadError>>,
-    fares: mpsc::Sender<Fare>,
-) {
-    for ride in rides {
+    mut rides: mpsc::Receiver<Result<Ride, ReadError>>,
+    fares: mpsc::Sender<CalculatedFare>,
+    tariff: Arc<Tariff>,
+    mut errors: Box<dyn ErrorSink>,
+    normalize: Option<TimestampConflict>,
+    max_in_flight: usize,
+    audit: bool,
+) -> Result<Summary, io::Error> {
+    let mut summary = Summary::default();
+    let slots = Arc::new(Semaphore::new(max_in_flight));
+
+    while let Some(ride) = rides.recv().await {
         match ride {
-            // real world scenario: do something with that error
             Err(err) => {
-                println!("{:?}", err)
+                summary.rejected_rows += 1;
+                errors.reject(Rejection::from(&err))?;
             }
-            Ok(ride) => {
+            Ok(mut ride) => {
+                if let Some(conflicts) = normalize {
+                    summary.normalized += ride.normalize(conflicts);
+                }
+                let slot = slots.clone().acquire_owned().await.unwrap();
+                let sequence = summary.rides;
+                summary.rides += 1;
                 let fares = fares.clone();
+                let tariff = tariff.clone();
                 tokio::spawn(async move {
-                    let amount = ride.calculate_fare().await;
-                    fares
-                        .send(Fare {
-                            id: (&ride).id,
-                            amount: Amount::from(amount),
+                    let fare = ride.calculate_fare(&tariff);
+                    let segments = match audit {
+                        true => ride.audit(&tariff),
+                        false => vec![],
+                    };
+                    // The receiver is only gone if writing the fares failed.
+                    let _ = fares
+                        .send(CalculatedFare {
+                            sequence,
+                            fare,
+                            segments,
+                            _slot: slot,
                         })
-                        .unwrap();
+                        .await;
                 });
             }
         }
     }
 
-    // rides
-    //     .into_par_iter()
-    //     .map(|ride| Fare {
-    //         id: ride.id,
-    //         amount: Amount::from(ride.calculate_fare()),
-    //     })
-    //     .collect()
+    Ok(summary)
 }
 
 impl Ride {
-    async fn calculate_fare(&self) -> f64 {
-        get_good_segments(self)
-            .iter()
-            .fold(STANDARD_FLAG, |fare, segment| fare + segment.get_fare())
-            .max(MINIMUM_FARE)
+    pub fn new(id: u32, positions: Vec<Position>) -> Self {
+        Ride { id, positions }
     }
-}
 
-fn get_good_segments(ride: &Ride) -> Vec<Segment> {
-    let mut previous_position: Option<Position> = None;
+    pub fn id(&self) -> u32 {
+        self.id
+    }
 
-    ride.to_owned()
-        .positions
-        .into_iter()
-        .filter_map(|current_pos| {
-            let prev_pos = match &previous_position {
+    pub fn positions(&self) -> &[Position] {
+        &self.positions
+    }
+
+    // Orders the positions by timestamp and leaves one per timestamp, as
+    // `Options::normalize` does for `estimate_fare`.
+    pub fn normalize(&mut self, conflicts: TimestampConflict) -> NormalizationReport {
+        normalization::normalize(&mut self.positions, conflicts)
+    }
+
+    // Prices the whole ride at once. `FareMeter` prices it position by
+    // position, by the same rules, and is checked against this.
+    pub fn calculate_fare(&self, tariff: &Tariff) -> Fare {
+        let round = |micros| tariff.rounding.round(micros, tariff.currency);
+        let to_amount = |micros| Amount::from_micros(round(micros), tariff.currency);
+        // Rounded per segment, every charge is rounded as it's added.
+        let round_charge = |micros| match tariff.rounding_point {
+            RoundingPoint::Segment => round(micros),
+            RoundingPoint::Total => micros,
+        };
+
+        let (mut day, mut night, mut idle) = (0, 0, 0);
+        let mut breakdown = FareBreakdown::new(tariff.currency);
+        for (segment, discarded) in consider_segments(self, tariff) {
+            if discarded.is_some() {
+                breakdown.discarded_segments += 1;
+                continue;
+            }
+
+            let charge = segment.get_charge(tariff);
+            breakdown.day_km += charge.day_km;
+            breakdown.night_km += charge.night_km;
+            breakdown.idle_seconds += charge.idle_seconds;
+            day += round_charge(charge.day_fare);
+            night += round_charge(charge.night_fare);
+            idle += round_charge(charge.idle_fare);
+        }
+
+        let flag = tariff.standard_flag.micros();
+        let fare = round(round_charge(flag) + day + night + idle);
+        let minimum_fare = round(tariff.minimum_fare.micros());
+        breakdown.flag = to_amount(flag);
+        breakdown.day_amount = to_amount(day);
+        breakdown.night_amount = to_amount(night);
+        breakdown.idle_amount = to_amount(idle);
+        breakdown.minimum_applied = minimum_fare > fare;
+
+        Fare {
+            id: self.id,
+            amount: Amount::from_micros(fare.max(minimum_fare), tariff.currency),
+            breakdown,
+        }
+    }
+
+    // The segments priced, after smoothing and filtering.
+    pub fn segments(&self, tariff: &Tariff) -> Vec<Segment> {
+        consider_segments(self, tariff)
+            .into_iter()
+            .filter_map(|(segment, discarded)| match discarded {
+                Some(_) => None,
+                None => Some(segment),
+            })
+            .collect()
+    }
+
+    // Every segment considered, priced or discarded.
+    pub fn audit(&self, tariff: &Tariff) -> Vec<AuditedSegment> {
+        consider_segments(self, tariff)
+            .into_iter()
+            .enumerate()
+            .map(|(index, (segment, discarded))| {
+                let classification = match discarded {
+                    Some(classification) => classification,
+                    None if segment.is_idle(tariff) => Classification::Idle,
+                    None if segment.is_day(tariff) => Classification::Day,
+                    None => Classification::Night,
+                };
+                let fare = match discarded {
+                    Some(_) => 0,
+                    None => segment.get_fare(tariff).micros(),
+                };
+
+                AuditedSegment {
+                    id_ride: self.id,
+                    index,
+                    start: segment.start.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    end: segment.end.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    distance_km: segment.distance_km,
+                    speed_kmh: segment.speed(),
+                    classification,
+                    amount: Amount::from_micros(
+                        tariff.rounding.round(fare, tariff.currency),
+                        tariff.currency,
+                    ),
+                }
+            })
+            .collect()
+    }
+}
+
+// Every segment from the last kept position to the next position, and how
+// it was discarded by the tariff's filters, if it was. Positions are smoothed
+// first if the tariff says so.
+fn consider_segments(ride: &Ride, tariff: &Tariff) -> Vec<Considered> {
+    let smoothed;
+    let positions = match tariff.smoothing {
+        Some(settings) => {
+            smoothed = smoothing::smooth(&ride.positions, settings);
+            &smoothed
+        }
+        None => &ride.positions,
+    };
+    let filters: Vec<_> = tariff.filters.iter().map(Filter::build).collect();
+    let mut distance = tariff.distance.for_ride(tariff.flat_earth);
+    let mut previous_position: Option<&Position> = None;
+    let mut previous_segment: Option<Segment> = None;
+
+    positions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, current_pos)| {
+            let prev_pos = match previous_position {
                 Some(prev_pos) => prev_pos,
                 None => {
                     previous_position = Some(current_pos);
@@ -184,23 +633,140 @@ fn get_good_segments(ride: &Ride) -> Vec<Segment> {
             let segment = Segment {
                 start: prev_pos.datetime,
                 end: current_pos.datetime,
-                distance_km: haversine::distance_km(&prev_pos.location, &current_pos.location),
+                distance_km: distance.km(&prev_pos.location, &current_pos.location),
             };
 
-            if is_too_fast(segment.speed()) {
-                return None;
+            let candidate = Candidate {
+                tariff,
+                distance: &distance,
+                start: prev_pos,
+                end: current_pos,
+                next: positions.get(index + 1),
+                segment: &segment,
+                previous: previous_segment.as_ref(),
+            };
+            let discarded = filters
+                .iter()
+                .find(|filter| !filter.keep(&candidate))
+                .map(|filter| filter.discarded());
+            if discarded.is_none() {
+                previous_position = Some(current_pos);
+                previous_segment = Some(segment.clone());
             }
-
-            previous_position = Some(current_pos);
-            Some(segment)
+            Some((segment, discarded))
         })
         .collect()
 }
 
 #[derive(Debug)]
 pub enum ReadError {
-    MissingValueError { field: String },
+    MissingValueError {
+        field: String,
+    },
+    FieldCountError {
+        expected: usize,
+        found: usize,
+    },
+    InvalidTimestampError {
+        value: String,
+    },
+    MissingColumnError {
+        column: String,
+    },
+    // A row of a ride whose rows came earlier, but not just before it.
+    ReappearingRideError {
+        id: u32,
+    },
+    NonFiniteCoordinateError {
+        field: String,
+    },
+    // An accuracy that's negative or not a number.
+    InvalidAccuracyError {
+        value: f64,
+    },
+    LatitudeRangeError {
+        value: f64,
+    },
+    LongitudeRangeError {
+        value: f64,
+    },
+    // A position at exactly 0,0, which is rejected unless the tariff allows
+    // it: GPS units often report it when they have no fix.
+    NullIslandError,
+    // A position outside the tariff's bounding box.
+    OutOfBoundsError {
+        latitude: f64,
+        longitude: f64,
+    },
     CSVError(csv::Error),
+    JSONError(serde_json::Error),
+    IOError(io::Error),
+    // A rejected input row: `line` is 1-based, `byte` is the offset of the
+    // start of the row and `raw` the row as it was in the input, without its
+    // line ending.
+    RowError {
+        line: u64,
+        byte: u64,
+        raw: String,
+        error: Box<ReadError>,
+    },
+}
+
+impl ReadError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReadError::MissingValueError { .. } => "missing_value",
+            ReadError::FieldCountError { .. } => "field_count",
+            ReadError::InvalidTimestampError { .. } => "invalid_timestamp",
+            ReadError::MissingColumnError { .. } => "missing_column",
+            ReadError::ReappearingRideError { .. } => "reappearing_ride",
+            ReadError::NonFiniteCoordinateError { .. } => "non_finite_coordinate",
+            ReadError::InvalidAccuracyError { .. } => "invalid_accuracy",
+            ReadError::LatitudeRangeError { .. } => "latitude_out_of_range",
+            ReadError::LongitudeRangeError { .. } => "longitude_out_of_range",
+            ReadError::NullIslandError => "null_island",
+            ReadError::OutOfBoundsError { .. } => "out_of_bounds",
+            ReadError::CSVError(_) => "csv",
+            ReadError::JSONError(_) => "json",
+            ReadError::IOError(_) => "io",
+            ReadError::RowError { error, .. } => error.kind(),
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::MissingValueError { field } => write!(f, "missing value for {}", field),
+            ReadError::FieldCountError { expected, found } => {
+                write!(f, "expected {} fields, found {}", expected, found)
+            }
+            ReadError::InvalidTimestampError { value } => write!(f, "invalid timestamp {}", value),
+            ReadError::MissingColumnError { column } => write!(f, "no column named {}", column),
+            ReadError::ReappearingRideError { id } => {
+                write!(f, "ride {} reappears after other rides", id)
+            }
+            ReadError::NonFiniteCoordinateError { field } => {
+                write!(f, "{} is not a finite number", field)
+            }
+            ReadError::InvalidAccuracyError { value } => write!(f, "invalid accuracy {}", value),
+            ReadError::LatitudeRangeError { value } => {
+                write!(f, "latitude {} is not between -90 and 90", value)
+            }
+            ReadError::LongitudeRangeError { value } => {
+                write!(f, "longitude {} is not between -180 and 180", value)
+            }
+            ReadError::NullIslandError => write!(f, "position is 0,0"),
+            ReadError::OutOfBoundsError {
+                latitude,
+                longitude,
+            } => write!(f, "position {},{} is out of bounds", latitude, longitude),
+            ReadError::CSVError(err) => write!(f, "{}", err),
+            ReadError::JSONError(err) => write!(f, "{}", err),
+            ReadError::IOError(err) => write!(f, "{}", err),
+            ReadError::RowError { line, error, .. } => write!(f, "line {}: {}", line, error),
+        }
+    }
 }
 
 impl From<csv::Error> for ReadError {
@@ -209,15 +775,29 @@ impl From<csv::Error> for ReadError {
     }
 }
 
-type Record = (Option<u32>, Option<f64>, Option<f64>, Option<i64>);
+impl From<serde_json::Error> for ReadError {
+    fn from(error: serde_json::Error) -> Self {
+        ReadError::JSONError(error)
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        ReadError::IOError(error)
+    }
+}
+
+type Record = (Option<u32>, Option<f64>, Option<f64>, Option<String>);
 
 type ParsedRecord = (Option<u32>, DateTime<chrono::Utc>, haversine::Location);
 
-fn parse_record(record: Record) -> Result<ParsedRecord, ReadError> {
+fn parse_record(record: Record, timestamps: TimestampFormat) -> Result<ParsedRecord, ReadError> {
     let (id, lat, lon, datetime) = record;
 
     let datetime: DateTime<Utc> = match datetime {
-        Some(ts) => Utc.timestamp(ts, 0),
+        Some(ts) => timestamps
+            .parse(&ts)
+            .ok_or(ReadError::InvalidTimestampError { value: ts })?,
         None => {
             return Err(ReadError::MissingValueError {
                 field: "datetime".to_string(),
@@ -242,103 +822,145 @@ fn parse_record(record: Record) -> Result<ParsedRecord, ReadError> {
             }
         },
     };
-
     Ok((id, datetime, loc))
 }
 
-fn read_csv(input: impl io::Read, parsed_records_tx: mpsc::Sender<Result<Ride, ReadError>>) {
-    let buffered = BufReader::new(input);
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .from_reader(buffered);
-
-    let mut current_ride_id: Option<u32> = None;
-    let mut positions: Vec<Position> = vec![];
-
-    for record in reader.deserialize() {
-        let record: Record = record.unwrap(); // todo error
-        let (id, datetime, location) = parse_record(record).unwrap(); // todo error
-
-        let valid_id = match id {
-            Some(id) => id,
-            None => {
-                parsed_records_tx
-                    .send(Err(ReadError::MissingValueError {
-                        field: "id".to_string(),
-                    }))
-                    .unwrap();
-                continue;
-            }
-        };
-
-        if let Some(cri) = current_ride_id {
-            if cri != valid_id {
-                parsed_records_tx
-                    .send(Ok(Ride {
-                        id: cri,
-                        positions: positions,
-                    }))
-                    .unwrap(); // TODO error
-                positions = vec![];
-            }
+// Whether `location` is a place on Earth at all.
+fn check_coordinates(location: &haversine::Location) -> Result<(), ReadError> {
+    for (field, value) in [
+        ("latitude", location.latitude),
+        ("longitude", location.longitude),
+    ] {
+        if !value.is_finite() {
+            return Err(ReadError::NonFiniteCoordinateError {
+                field: field.to_string(),
+            });
         }
-
-        positions.push(Position { datetime, location });
-        current_ride_id = Some(valid_id);
     }
+    if !(-90.0..=90.0).contains(&location.latitude) {
+        return Err(ReadError::LatitudeRangeError {
+            value: location.latitude,
+        });
+    }
+    if !(-180.0..=180.0).contains(&location.longitude) {
+        return Err(ReadError::LongitudeRangeError {
+            value: location.longitude,
+        });
+    }
+    Ok(())
+}
 
-    parsed_records_tx
-        .send(Ok(Ride {
-            id: current_ride_id.unwrap(),
-            positions,
-        }))
-        .unwrap();
+fn read_rides(
+    input: impl io::Read,
+    format: InputFormat,
+    timestamps: TimestampFormat,
+    grouping: RideGrouping,
+    tariff: Arc<Tariff>,
+    parsed_records_tx: mpsc::Sender<Result<Ride, ReadError>>,
+) -> Result<(), ReadError> {
+    match format {
+        InputFormat::Csv(format) => group_rides(
+            CheckedPositions::new(CsvPositions::new(input, format, timestamps), tariff),
+            grouping,
+            parsed_records_tx,
+        ),
+        InputFormat::JsonLines => group_rides(
+            CheckedPositions::new(JsonLinesPositions::new(input, timestamps), tariff),
+            grouping,
+            parsed_records_tx,
+        ),
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct Fare {
+// A ride's fare, and how it was made up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fare {
     id: u32,
     amount: Amount,
+    breakdown: FareBreakdown,
 }
 
-impl PartialEq for Fare {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id && self.amount == other.amount
+impl Fare {
+    pub fn new(id: u32, amount: Amount, breakdown: FareBreakdown) -> Self {
+        Fare {
+            id,
+            amount,
+            breakdown,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
     }
-}
 
-// Amount get rounded to 2 decimal places when serialized
-#[derive(Debug)]
-struct Amount(f64);
-impl PartialEq for Amount {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+    pub fn amount(&self) -> Amount {
+        self.amount
     }
-}
-impl std::convert::From<f64> for Amount {
-    fn from(f: f64) -> Self {
-        Self(f)
+
+    pub fn breakdown(&self) -> &FareBreakdown {
+        &self.breakdown
     }
 }
-impl Serialize for Amount {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(&format!("{:.2}", self.0))
+
+// Holds back values that arrive before their turn, releasing them in
+// sequence order.
+struct ReorderBuffer<T> {
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> ReorderBuffer<T> {
+    fn new() -> Self {
+        ReorderBuffer {
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, sequence: usize, value: T) {
+        self.pending.insert(sequence, value);
+    }
+
+    fn pop_ready(&mut self) -> Option<T> {
+        let value = self.pending.remove(&self.next)?;
+        self.next += 1;
+        Some(value)
     }
 }
 
-fn write_csv(output: impl io::Write, fares: mpsc::Receiver<Fare>) -> Result<(), io::Error> {
-    let mut writer = csv::WriterBuilder::new()
-        .has_headers(false)
-        .from_writer(output);
+fn write_fares(
+    mut writer: FareWriter<impl io::Write>,
+    mut fares: mpsc::Receiver<CalculatedFare>,
+    preserve_order: bool,
+    mut audit: Option<Box<dyn AuditSink>>,
+) -> Result<(), io::Error> {
+    let mut write = |calculated: CalculatedFare| -> Result<(), io::Error> {
+        writer.write(&calculated.fare)?;
+        if let Some(audit) = audit.as_mut() {
+            for segment in calculated.segments {
+                audit.record(segment)?;
+            }
+        }
+        Ok(())
+    };
+    let mut reorder_buffer = ReorderBuffer::new();
 
-    for fare in fares {
-        writer.serialize(fare)?;
+    while let Some(calculated) = fares.blocking_recv() {
+        if !preserve_order {
+            write(calculated)?;
+            continue;
+        }
+
+        reorder_buffer.push(calculated.sequence, calculated);
+        while let Some(calculated) = reorder_buffer.pop_ready() {
+            write(calculated)?;
+        }
     }
 
-    writer.flush()?;
+    writer.finish()?;
+    if let Some(audit) = audit.as_mut() {
+        audit.flush()?;
+    }
 
     Ok(())
 }
@@ -366,14 +988,26 @@ fn segment_duration() {
         end: Utc.ymd(2019, 1, 1).and_hms(2, 0, 0),
         distance_km: 50.0,
     };
-    assert_eq!(7200, day_segment.duration_seconds());
+    assert_eq!(7200.0, day_segment.duration_seconds());
 
     let night_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(0, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(0, 30, 0),
         distance_km: 200.0,
     };
-    assert_eq!(1800, night_segment.duration_seconds());
+    assert_eq!(1800.0, night_segment.duration_seconds());
+}
+
+#[test]
+fn segment_duration_below_a_second() {
+    let segment = Segment {
+        start: Utc.timestamp_millis(1405594957000),
+        end: Utc.timestamp_millis(1405594957250),
+        distance_km: 0.005,
+    };
+
+    assert_eq!(0.25, segment.duration_seconds());
+    assert!((72.0 - segment.speed()).abs() < 1e-9);
 }
 
 #[test]
@@ -383,21 +1017,66 @@ fn segment_fare() {
         end: Utc.ymd(2019, 1, 1).and_hms(12, 0, 0),
         distance_km: 50.0,
     };
-    assert_eq!(37.0, day_segment.get_fare());
+    assert_eq!(
+        37_000_000,
+        day_segment.get_fare(&Tariff::default()).micros()
+    );
 
     let idle_day_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(10, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(11, 0, 0),
         distance_km: 0.0,
     };
-    assert_eq!(11.90, idle_day_segment.get_fare());
+    assert_eq!(
+        11_900_000,
+        idle_day_segment.get_fare(&Tariff::default()).micros()
+    );
 
     let night_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(1, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(1, 30, 0),
         distance_km: 200.0,
     };
-    assert_eq!(260.0, night_segment.get_fare());
+    assert_eq!(
+        260_000_000,
+        night_segment.get_fare(&Tariff::default()).micros()
+    );
+}
+
+#[test]
+fn segment_fare_across_boundaries() {
+    let tariff = Tariff::default();
+
+    // 601 seconds of night, then 599 seconds of day.
+    let dawn_segment = Segment {
+        start: Utc.ymd(2019, 1, 1).and_hms(4, 50, 0),
+        end: Utc.ymd(2019, 1, 1).and_hms(5, 10, 0),
+        distance_km: 12.0,
+    };
+    // 1.30 * 12 * 601 / 1200 + 0.74 * 12 * 599 / 1200, to a micro per piece.
+    assert!((12_245_600 - dawn_segment.get_fare(&tariff).micros()).abs() <= 2);
+
+    // 2 hours of day, 5 hours of night, 2 hours of day.
+    let overnight_segment = Segment {
+        start: Utc.ymd(2019, 1, 1).and_hms(22, 0, 0),
+        end: Utc.ymd(2019, 1, 2).and_hms(7, 0, 0),
+        distance_km: 180.0,
+    };
+    let pieces = overnight_segment.split(&tariff);
+    assert_eq!(3, pieces.len());
+    assert!((180.0 * 7201.0 / 32400.0 - pieces[0].distance_km).abs() < 1e-9);
+    assert!((100.0 - pieces[1].distance_km).abs() < 1e-9);
+    assert!((180.0 * 7199.0 / 32400.0 - pieces[2].distance_km).abs() < 1e-9);
+    // 0.74 * 80 + 1.30 * 100.
+    assert!((189_200_000 - overnight_segment.get_fare(&tariff).micros()).abs() <= 3);
+
+    // Idle time is billed the same by day and by night.
+    let idle_segment = Segment {
+        start: Utc.ymd(2019, 1, 1).and_hms(4, 30, 0),
+        end: Utc.ymd(2019, 1, 1).and_hms(5, 30, 0),
+        distance_km: 1.0,
+    };
+    assert_eq!(11_900_000, idle_segment.get_fare(&tariff).micros());
 }
 
 #[test]
@@ -407,21 +1086,21 @@ fn segment_is_idle() {
         end: Utc.ymd(2019, 1, 1).and_hms(11, 0, 0),
         distance_km: 0.0,
     };
-    assert_eq!(true, idle_segment.is_idle());
+    assert!(idle_segment.is_idle(&Tariff::default()));
 
     let barely_idle_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(10, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(11, 0, 0),
         distance_km: 10.0,
     };
-    assert_eq!(true, barely_idle_segment.is_idle());
+    assert!(barely_idle_segment.is_idle(&Tariff::default()));
 
     let moving_idle_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(10, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(11, 0, 0),
         distance_km: 50.0,
     };
-    assert_eq!(false, moving_idle_segment.is_idle());
+    assert!(!moving_idle_segment.is_idle(&Tariff::default()));
 }
 
 #[test]
@@ -431,41 +1110,41 @@ fn segment_is_day() {
         end: Utc.ymd(2019, 1, 1).and_hms(12, 0, 0),
         distance_km: 50.0,
     };
-    assert_eq!(true, day_segment.is_day());
+    assert!(day_segment.is_day(&Tariff::default()));
 
     let late_day_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(0, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(0, 30, 0),
         distance_km: 200.0,
     };
-    assert_eq!(true, late_day_segment.is_day());
+    assert!(late_day_segment.is_day(&Tariff::default()));
 
     let night_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(5, 0, 0),
         end: Utc.ymd(2019, 1, 1).and_hms(20, 30, 0),
         distance_km: 200.0,
     };
-    assert_eq!(false, night_segment.is_day());
+    assert!(!night_segment.is_day(&Tariff::default()));
 
     let early_night_segment = Segment {
         start: Utc.ymd(2019, 1, 1).and_hms(0, 0, 1),
         end: Utc.ymd(2019, 1, 1).and_hms(0, 30, 0),
         distance_km: 200.0,
     };
-    assert_eq!(false, early_night_segment.is_day());
+    assert!(!early_night_segment.is_day(&Tariff::default()));
 }
 
 #[test]
 fn it_is_too_fast() {
-    for speed in vec![120.0, 150.0, 999999.999] {
-        assert_eq!(true, is_too_fast(speed))
+    for speed in [120.0, 150.0, 999999.999] {
+        assert!(is_too_fast(speed, &Tariff::default()))
     }
 }
 
 #[test]
 fn it_is_not_too_fast() {
-    for speed in vec![0.1, 20.0, 50.3, 99.999] {
-        assert_eq!(false, is_too_fast(speed))
+    for speed in [0.1, 20.0, 50.3, 99.999] {
+        assert!(!is_too_fast(speed, &Tariff::default()))
     }
 }
 
@@ -485,6 +1164,7 @@ async fn test_calculate_all_fares() {
                         latitude: 38.9,
                         longitude: -77.0,
                     },
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(5, 0, 0),
@@ -492,6 +1172,7 @@ async fn test_calculate_all_fares() {
                         latitude: 38.9,
                         longitude: -78.0,
                     }, // ± 87km from previous position
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(6, 0, 0),
@@ -499,47 +1180,60 @@ async fn test_calculate_all_fares() {
                         latitude: 38.9,
                         longitude: -77.0,
                     }, // ± 87km from previous position
+                    accuracy: None,
                 },
             ],
         },
     ];
 
-    let want = vec![
-        Fare {
-            id: 1,
-            amount: Amount::from(MINIMUM_FARE),
-        },
-        Fare {
-            id: 2,
-            amount: Amount::from(226.29426737040808),
-        },
+    let want = [
+        (1, Amount::new(347, Currency::EUR)),
+        (2, Amount::new(17785, Currency::EUR)),
     ];
 
-    let (parsed_records_tx, parsed_records_rx) = mpsc::channel();
+    let (parsed_records_tx, parsed_records_rx) = mpsc::channel(2);
     for ride in rides {
-        parsed_records_tx.send(Ok(ride)).unwrap();
+        parsed_records_tx.send(Ok(ride)).await.unwrap();
     }
     drop(parsed_records_tx);
 
-    let (fares_tx, fares_rx) = mpsc::channel();
-
-    calculate_all_fares(parsed_records_rx, fares_tx).await;
-    let got: Vec<Fare> = fares_rx.into_iter().collect();
+    let (fares_tx, mut fares_rx) = mpsc::channel(2);
+
+    calculate_all_fares(
+        parsed_records_rx,
+        fares_tx,
+        Arc::new(Tariff::default()),
+        Options::default().errors,
+        None,
+        2,
+        false,
+    )
+    .await
+    .unwrap();
+    let mut got = vec![];
+    while let Some(calculated) = fares_rx.recv().await {
+        got.push(calculated);
+    }
+    got.sort_by_key(|calculated| calculated.sequence);
+    let got: Vec<(u32, Amount)> = got
+        .into_iter()
+        .map(|calculated| (calculated.fare.id, calculated.fare.amount))
+        .collect();
 
     assert_eq!(2, got.len());
     assert_eq!(want[0], got[0]);
     assert_eq!(want[1], got[1]);
 }
 
-#[tokio::test(flavor = "multi_thread")]
-async fn ride_fare() {
-    for (ride, want) in vec![
+#[test]
+fn ride_fare() {
+    for (ride, want) in [
         (
             Ride {
                 id: 1,
                 positions: vec![],
             },
-            MINIMUM_FARE,
+            Amount::new(347, Currency::EUR),
         ),
         (
             Ride {
@@ -551,6 +1245,7 @@ async fn ride_fare() {
                             latitude: 38.9,
                             longitude: -77.0,
                         },
+                        accuracy: None,
                     },
                     Position {
                         datetime: Utc.ymd(2020, 10, 20).and_hms(5, 0, 0),
@@ -558,6 +1253,7 @@ async fn ride_fare() {
                             latitude: 38.9,
                             longitude: -78.0,
                         }, // ± 87km from previous position
+                        accuracy: None,
                     },
                     Position {
                         datetime: Utc.ymd(2020, 10, 20).and_hms(6, 0, 0),
@@ -565,13 +1261,940 @@ async fn ride_fare() {
                             latitude: 38.9,
                             longitude: -77.0,
                         }, // ± 87km from previous position
+                        accuracy: None,
                     },
                 ],
             },
-            226.29426737040808,
+            Amount::new(17785, Currency::EUR),
         ),
     ] {
-        assert_eq!(want, ride.calculate_fare().await)
+        assert_eq!(want, ride.calculate_fare(&Tariff::default()).amount)
+    }
+}
+
+#[test]
+fn ride_fare_rounding() {
+    // Three idle quarters of an hour, 2.975 each, plus the 1.30 flag.
+    let ride = Ride {
+        id: 1,
+        positions: (0..4)
+            .map(|quarter| Position {
+                datetime: Utc.ymd(2020, 10, 20).and_hms(10, 15 * quarter, 0),
+                location: haversine::Location {
+                    latitude: 38.9,
+                    longitude: -77.0,
+                },
+                accuracy: None,
+            })
+            .collect(),
+    };
+
+    for (rounding, rounding_point, want) in [
+        (Rounding::HalfUp, RoundingPoint::Total, 1023),
+        (Rounding::HalfEven, RoundingPoint::Total, 1022),
+        (Rounding::Cash, RoundingPoint::Total, 1025),
+        (Rounding::HalfUp, RoundingPoint::Segment, 1024),
+        (Rounding::HalfEven, RoundingPoint::Segment, 1024),
+        (Rounding::Cash, RoundingPoint::Segment, 1030),
+    ] {
+        let tariff = Tariff {
+            rounding,
+            rounding_point,
+            ..Tariff::default()
+        };
+        assert_eq!(
+            Amount::new(want, Currency::EUR),
+            ride.calculate_fare(&tariff).amount,
+            "{:?} {:?}",
+            rounding,
+            rounding_point
+        );
+    }
+}
+
+#[test]
+fn ride_from_constructors() {
+    let position = |min, longitude| {
+        Position::new(
+            Utc.ymd(2020, 10, 20).and_hms(12, min, 0),
+            Location {
+                latitude: 38.9,
+                longitude,
+            },
+            Some(5.0),
+        )
+        .unwrap()
+    };
+    let mut ride = Ride::new(
+        7,
+        vec![
+            position(30, -77.05),
+            position(0, -77.0),
+            position(30, -77.05),
+        ],
+    );
+
+    assert_eq!(1, ride.normalize(TimestampConflict::default()).reordered);
+    assert_eq!(7, ride.id());
+    assert_eq!(2, ride.positions().len());
+    assert_eq!(Some(5.0), ride.positions()[1].accuracy());
+    assert_eq!(-77.05, ride.positions()[1].location().longitude);
+
+    let segments = ride.segments(&Tariff::default());
+    assert_eq!(1, segments.len());
+    assert_eq!(ride.positions()[0].datetime(), segments[0].start());
+    assert_eq!(1800.0, segments[0].duration_seconds());
+    assert!((segments[0].distance_km() - 4.33).abs() < 0.01);
+    assert!((segments[0].speed() - 8.66).abs() < 0.02);
+    assert_eq!(
+        Price::from_micros(5_950_000),
+        segments[0].get_fare(&Tariff::default())
+    );
+
+    // Idle for half an hour.
+    let fare = ride.calculate_fare(&Tariff::default());
+    assert_eq!(7, fare.id());
+    assert_eq!(725, fare.amount().minor_units());
+    assert_eq!(1800.0, fare.breakdown().idle_seconds);
+    assert_eq!(595, fare.breakdown().idle_amount.minor_units());
+    assert_eq!(fare, Fare::new(7, fare.amount(), fare.breakdown().clone()));
+}
+
+#[test]
+fn rides_from_serde() {
+    let json = r#"{"id":7,"positions":[{"datetime":"2020-10-20T12:00:00Z","location":{"latitude":38.9,"longitude":-77.0},"accuracy":5.0}]}"#;
+
+    let ride: Ride = serde_json::from_str(json).unwrap();
+    assert_eq!(7, ride.id());
+    assert_eq!(
+        Utc.ymd(2020, 10, 20).and_hms(12, 0, 0),
+        ride.positions()[0].datetime()
+    );
+    assert_eq!(Some(5.0), ride.positions()[0].accuracy());
+    assert_eq!(json, serde_json::to_string(&ride).unwrap());
+
+    // Positions are checked as they are read.
+    let invalid = json.replace("38.9", "91.0");
+    assert!(serde_json::from_str::<Ride>(&invalid).is_err());
+}
+
+#[test]
+fn position_rejects_invalid_values() {
+    let datetime = Utc.ymd(2020, 10, 20).and_hms(12, 0, 0);
+    let location = |latitude, longitude| Location {
+        latitude,
+        longitude,
+    };
+
+    assert!(matches!(
+        Position::new(datetime, location(91.0, 23.72), None),
+        Err(ReadError::LatitudeRangeError { .. })
+    ));
+    assert!(matches!(
+        Position::new(datetime, location(37.96, f64::NAN), None),
+        Err(ReadError::NonFiniteCoordinateError { .. })
+    ));
+    assert!(matches!(
+        Position::new(datetime, location(37.96, 23.72), Some(-1.0)),
+        Err(ReadError::InvalidAccuracyError { .. })
+    ));
+}
+
+#[test]
+fn ride_fare_breakdown() {
+    let position = |hour, min, sec, longitude| Position {
+        datetime: Utc.ymd(2020, 10, 20).and_hms(hour, min, sec),
+        location: haversine::Location {
+            latitude: 38.9,
+            longitude,
+        },
+        accuracy: None,
+    };
+    // Night, too fast, across the end of the night, then idle.
+    let ride = Ride {
+        id: 1,
+        positions: vec![
+            position(4, 0, 0, -77.0),
+            position(4, 30, 0, -77.5),
+            position(4, 30, 10, -78.0),
+            position(5, 30, 0, -77.0),
+            position(5, 45, 0, -77.0),
+        ],
+    };
+    let leg_km = haversine::distance_km(&ride.positions[0].location, &ride.positions[1].location);
+    let eur = |minor_units| Amount::new(minor_units, Currency::EUR);
+
+    let fare = ride.calculate_fare(&Tariff::default());
+    let breakdown = fare.breakdown;
+
+    assert_eq!(eur(130), breakdown.flag);
+    assert!((leg_km * 5401.0 / 3600.0 - breakdown.night_km).abs() < 1e-9);
+    assert!((leg_km * 1799.0 / 3600.0 - breakdown.day_km).abs() < 1e-9);
+    assert_eq!(900.0, breakdown.idle_seconds);
+    assert_eq!(eur(298), breakdown.idle_amount);
+    assert_eq!(1, breakdown.discarded_segments);
+    assert!(!breakdown.minimum_applied);
+    let parts = breakdown.flag.minor_units()
+        + breakdown.day_amount.minor_units()
+        + breakdown.night_amount.minor_units()
+        + breakdown.idle_amount.minor_units();
+    assert!((fare.amount.minor_units() - parts).abs() <= 1);
+
+    let empty = Ride {
+        id: 2,
+        positions: vec![],
+    };
+    let fare = empty.calculate_fare(&Tariff::default());
+    assert_eq!(eur(347), fare.amount);
+    assert_eq!(
+        FareBreakdown {
+            flag: eur(130),
+            minimum_applied: true,
+            ..FareBreakdown::new(Currency::EUR)
+        },
+        fare.breakdown
+    );
+}
+
+#[test]
+fn ride_fare_with_smoothing() {
+    // Waiting for 10 minutes, with about 10 m of GPS jitter every second.
+    let waiting = |jitter: f64| Ride {
+        id: 1,
+        positions: (0..600)
+            .map(|i| {
+                let jitter = (i as f64 * 2.4).sin() * jitter;
+                Position {
+                    datetime: Utc.ymd(2020, 10, 20).and_hms(12, 0, 0)
+                        + chrono::Duration::seconds(i),
+                    location: haversine::Location {
+                        latitude: 37.96 + jitter,
+                        longitude: 23.72 - jitter,
+                    },
+                    accuracy: None,
+                }
+            })
+            .collect(),
+    };
+    let smoothing = Tariff {
+        smoothing: Some(Smoothing::default()),
+        ..Tariff::default()
+    };
+
+    let still = waiting(0.0).calculate_fare(&Tariff::default());
+    let raw = waiting(0.0001).calculate_fare(&Tariff::default());
+    let smoothed = waiting(0.0001).calculate_fare(&smoothing);
+
+    // The jitter alone adds up to some 10 km at the day rate.
+    assert!(raw.breakdown.day_km > 5.0);
+    assert!(raw.amount.minor_units() > still.amount.minor_units() + 300);
+    assert!(smoothed.breakdown.day_km < 0.1);
+    assert_eq!(still.amount, smoothed.amount);
+}
+
+#[test]
+fn ride_fare_with_wgs84_distance() {
+    // From the airport to Syntagma, in one 30 minute segment at the day rate.
+    let position = |min, latitude, longitude| Position {
+        datetime: Utc.ymd(2020, 10, 20).and_hms(12, min, 0),
+        location: haversine::Location {
+            latitude,
+            longitude,
+        },
+        accuracy: None,
+    };
+    let ride = Ride {
+        id: 1,
+        positions: vec![position(0, 37.936, 23.944), position(30, 37.976, 23.736)],
+    };
+    let wgs84 = Tariff {
+        distance: Distance::Wgs84,
+        ..Tariff::default()
+    };
+
+    let spherical = ride.calculate_fare(&Tariff::default());
+    let ellipsoidal = ride.calculate_fare(&wgs84);
+
+    assert_eq!(
+        geodesy::vincenty_km(&ride.positions[0].location, &ride.positions[1].location),
+        ellipsoidal.breakdown.day_km
+    );
+    assert!(ellipsoidal.breakdown.day_km > spherical.breakdown.day_km);
+    assert!(ellipsoidal.amount.minor_units() > spherical.amount.minor_units());
+}
+
+#[test]
+fn ride_audit() {
+    let position = |hour, min, sec, longitude| Position {
+        datetime: Utc.ymd(2020, 10, 20).and_hms(hour, min, sec),
+        location: haversine::Location {
+            latitude: 38.9,
+            longitude,
+        },
+        accuracy: None,
+    };
+    let ride = Ride {
+        id: 1,
+        positions: vec![
+            position(4, 0, 0, -77.0),
+            position(4, 30, 0, -77.5),
+            position(4, 30, 10, -78.0),
+            position(5, 30, 0, -77.0),
+            position(5, 45, 0, -77.0),
+        ],
+    };
+
+    let segments = ride.audit(&Tariff::default());
+
+    let summary: Vec<_> = segments
+        .iter()
+        .map(|segment| {
+            (
+                segment.index,
+                &segment.start[11..19],
+                &segment.end[11..19],
+                segment.classification,
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            (0, "04:00:00", "04:30:00", Classification::Night),
+            (1, "04:30:00", "04:30:10", Classification::DiscardedTooFast),
+            (2, "04:30:00", "05:30:00", Classification::Night),
+            (3, "05:30:00", "05:45:00", Classification::Idle),
+        ],
+        summary
+    );
+    assert!(segments[1].speed_kmh > 100.0);
+    assert_eq!(0, segments[1].amount.minor_units());
+    assert_eq!(298, segments[3].amount.minor_units());
+    assert_eq!(0.0, segments[3].distance_km);
+    assert!(segments.iter().all(|segment| segment.id_ride == 1));
+}
+
+#[test]
+fn ride_audit_with_filters() {
+    let position = |sec, latitude, accuracy| Position {
+        datetime: Utc.ymd(2020, 10, 20).and_hms(12, 0, sec),
+        location: haversine::Location {
+            latitude,
+            longitude: 23.72,
+        },
+        accuracy,
+    };
+    let ride = Ride {
+        id: 1,
+        positions: vec![
+            position(0, 37.96, Some(5.0)),
+            position(10, 37.9601, Some(5.0)),
+            position(20, 37.961, Some(80.0)),
+            position(30, 37.962, Some(5.0)),
+            position(40, 37.964, None),
+            position(50, 37.963, Some(5.0)),
+        ],
+    };
+    let tariff = Tariff {
+        filters: vec![
+            Filter::MaxSpeed,
+            Filter::MinDisplacement { min_distance: 20.0 },
+            Filter::Accuracy { max_accuracy: 25.0 },
+            Filter::Spike {
+                max_turn: 150.0,
+                min_leg: 50.0,
+            },
+        ],
+        ..Tariff::default()
+    };
+
+    let segments = ride.audit(&tariff);
+
+    let summary: Vec<_> = segments
+        .iter()
+        .map(|segment| {
+            (
+                &segment.start[17..19],
+                &segment.end[17..19],
+                segment.classification,
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            ("00", "10", Classification::DiscardedTooShort),
+            ("00", "20", Classification::DiscardedInaccurate),
+            ("00", "30", Classification::Day),
+            ("30", "40", Classification::DiscardedSpike),
+            ("30", "50", Classification::Day),
+        ],
+        summary
+    );
+    assert_eq!(
+        Some(Classification::DiscardedTooShort),
+        consider_segments(&ride, &tariff)[0].1
+    );
+    // By default, all of them are kept.
+    assert!(consider_segments(&ride, &Tariff::default())
+        .iter()
+        .all(|(_, discarded)| discarded.is_none()));
+}
+
+#[cfg(test)]
+mod read_csv_tests {
+    use super::*;
+
+    fn read(input: &str) -> (Vec<Result<Ride, ReadError>>, Result<(), ReadError>) {
+        let (tx, mut rx) = mpsc::channel(16);
+        let result = read_rides(
+            input.as_bytes(),
+            InputFormat::default(),
+            TimestampFormat::default(),
+            RideGrouping::default(),
+            Arc::new(Tariff::default()),
+            tx,
+        );
+        (std::iter::from_fn(|| rx.blocking_recv()).collect(), result)
+    }
+
+    #[test]
+    fn it_groups_rows_into_rides() {
+        let (rides, result) =
+            read("1,37.96,23.72,1405594957\n1,37.97,23.73,1405594966\n2,37.96,23.72,1405594970\n");
+
+        assert!(result.is_ok());
+        assert_eq!(2, rides.len());
+        let first = rides[0].as_ref().unwrap();
+        assert_eq!((1, 2), (first.id, first.positions.len()));
+        let second = rides[1].as_ref().unwrap();
+        assert_eq!((2, 1), (second.id, second.positions.len()));
+    }
+
+    #[test]
+    fn it_reports_bad_rows_and_keeps_going() {
+        let input = "1,37.96,23.72,1405594957\n\
+                     1,north,23.73,1405594966\n\
+                     ,37.96,23.72,1405594970\n\
+                     1,37.96,23.72\n\
+                     1,37.97,23.73,1405594980\n";
+        let (rides, result) = read(input);
+
+        assert!(result.is_ok());
+        assert_eq!(4, rides.len());
+
+        for (got, (want_line, want_byte, want_raw)) in rides.iter().zip([
+            (2, 25, "1,north,23.73,1405594966"),
+            (3, 50, ",37.96,23.72,1405594970"),
+            (4, 74, "1,37.96,23.72"),
+        ]) {
+            match got {
+                Err(ReadError::RowError {
+                    line, byte, raw, ..
+                }) => {
+                    assert_eq!(want_line, *line);
+                    assert_eq!(want_byte, *byte);
+                    assert_eq!(want_raw, raw);
+                }
+                other => panic!("expected a row error, got {:?}", other.as_ref().err()),
+            }
+        }
+
+        match &rides[1] {
+            Err(ReadError::RowError { error, .. }) => assert!(matches!(
+                **error,
+                ReadError::MissingValueError { ref field } if field == "id"
+            )),
+            _ => unreachable!(),
+        }
+        match &rides[2] {
+            Err(ReadError::RowError { error, .. }) => assert!(matches!(
+                **error,
+                ReadError::FieldCountError {
+                    expected: 4,
+                    found: 3
+                }
+            )),
+            _ => unreachable!(),
+        }
+
+        let ride = rides[3].as_ref().unwrap();
+        assert_eq!((1, 2), (ride.id, ride.positions.len()));
+    }
+
+    #[test]
+    fn it_rejects_invalid_coordinates() {
+        let input = "1,37.96,23.72,1405594957\n\
+                     1,NaN,23.72,1405594966\n\
+                     1,37.96,inf,1405594966\n\
+                     1,237.9,23.72,1405594966\n\
+                     1,37.96,-180.5,1405594966\n\
+                     1,0,0,1405594966\n\
+                     1,90,-180,1405594980\n";
+        let (rides, result) = read(input);
+
+        assert!(result.is_ok());
+        let kinds: Vec<_> = rides
+            .iter()
+            .map(|ride| match ride {
+                Ok(ride) => ride.positions.len().to_string(),
+                Err(err) => err.kind().to_string(),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "non_finite_coordinate",
+                "non_finite_coordinate",
+                "latitude_out_of_range",
+                "longitude_out_of_range",
+                "null_island",
+                "2",
+            ],
+            kinds
+        );
+    }
+
+    #[test]
+    fn it_accepts_empty_input() {
+        let (rides, result) = read("");
+
+        assert!(result.is_ok());
+        assert!(rides.is_empty());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_skips_bad_rows() {
+    let input = "1,37.966660,23.728308,1405594957\nbad row\n1,37.966627,23.728263,1405594966\n";
+    let output = SharedBuffer::default();
+
+    let (rejections_tx, rejections_rx) = std::sync::mpsc::channel();
+    let options = Options {
+        errors: Box::new(move |rejection| rejections_tx.send(rejection).unwrap()),
+        ..Options::default()
+    };
+
+    let summary = estimate_fare(input.as_bytes(), output.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        Summary {
+            rides: 1,
+            rejected_rows: 1,
+            ..Summary::default()
+        },
+        summary
+    );
+    assert_eq!("1,3.47\n", output.contents());
+    assert_eq!(
+        vec![Rejection {
+            line: Some(2),
+            byte: Some(33),
+            kind: "field_count",
+            reason: "expected 4 fields, found 1".to_string(),
+            raw: Some("bad row".to_string()),
+        }],
+        rejections_rx.into_iter().collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_with_breakdown() {
+    let input = "1,37.966660,23.728308,1405594957\n1,37.966627,23.728263,1405594966\n";
+    let output = SharedBuffer::default();
+    let options = Options {
+        breakdown: true,
+        ..Options::default()
+    };
+
+    estimate_fare(input.as_bytes(), output.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        "id_ride,fare_estimate,flag,day_km,day_amount,night_km,night_amount,idle_seconds,idle_amount,discarded_segments,minimum_applied\n\
+         1,3.47,1.30,0.0,0.00,0.0,0.00,9.0,0.03,0,true\n",
+        output.contents()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_with_audit() {
+    let input = "1,37.966660,23.728308,1405594957\n1,37.966627,23.728263,1405594966\n\
+                 2,37.966660,23.728308,1405594957\n2,37.966627,23.728263,1405594966\n\
+                 2,37.966660,23.728308,1405594975\n";
+    let (segments_tx, segments_rx) = std::sync::mpsc::channel();
+    let options = Options {
+        audit: Some(Box::new(move |segment| segments_tx.send(segment).unwrap())),
+        ..Options::default()
+    };
+
+    estimate_fare(input.as_bytes(), io::sink(), options)
+        .await
+        .unwrap();
+
+    let segments: Vec<_> = segments_rx
+        .into_iter()
+        .map(|segment| (segment.id_ride, segment.index, segment.classification))
+        .collect();
+    assert_eq!(
+        vec![
+            (1, 0, Classification::Idle),
+            (2, 0, Classification::Idle),
+            (2, 1, Classification::Idle),
+        ],
+        segments
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_from_json_lines() {
+    let csv = "1,37.966660,23.728308,1405594957\n1,37.966627,23.728263,1405594966\n\
+               2,37.946545,23.754918,1405591065\n2,37.946545,23.754918,1405591073\n\
+               2,37.926545,23.784918,1405591084\n";
+    let json_lines: String = csv
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            format!(
+                "{{\"id_ride\":{},\"lat\":{},\"lng\":{},\"timestamp\":{}}}\n",
+                fields[0], fields[1], fields[2], fields[3]
+            )
+        })
+        .collect();
+
+    let from_csv = SharedBuffer::default();
+    estimate_fare(csv.as_bytes(), from_csv.clone(), Options::default())
+        .await
+        .unwrap();
+    let from_json_lines = SharedBuffer::default();
+    let options = Options {
+        input_format: InputFormat::JsonLines,
+        ..Options::default()
+    };
+    estimate_fare(
+        io::Cursor::new(json_lines),
+        from_json_lines.clone(),
+        options,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(2, from_csv.contents().lines().count());
+    assert_eq!(from_csv.contents(), from_json_lines.contents());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_grouping_by_id() {
+    let contiguous = "1,37.966660,23.728308,1405594957\n1,37.966627,23.728263,1405594966\n\
+                      2,37.946545,23.754918,1405591065\n2,37.926545,23.784918,1405591084\n";
+    let interleaved = "2,37.946545,23.754918,1405591065\n1,37.966660,23.728308,1405594957\n\
+                       2,37.926545,23.784918,1405591084\n1,37.966627,23.728263,1405594966\n";
+
+    let want = SharedBuffer::default();
+    estimate_fare(contiguous.as_bytes(), want.clone(), Options::default())
+        .await
+        .unwrap();
+    let got = SharedBuffer::default();
+    let options = Options {
+        grouping: RideGrouping::ById {
+            max_positions_in_memory: 3,
+        },
+        ..Options::default()
+    };
+    let summary = estimate_fare(interleaved.as_bytes(), got.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(2, summary.rides);
+    assert_eq!(want.contents(), got.contents());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_normalizing_positions() {
+    let ordered = "1,37.966660,23.728308,1405594957\n1,37.966627,23.728263,1405594966\n\
+                   1,37.946545,23.754918,1405595065\n";
+    let shuffled = "1,37.966627,23.728263,1405594966\n1,37.966660,23.728308,1405594957\n\
+                    1,37.946545,23.754918,1405595065\n1,37.966627,23.728263,1405594966\n\
+                    1,37.956545,23.754918,1405595065\n";
+
+    let want = SharedBuffer::default();
+    estimate_fare(ordered.as_bytes(), want.clone(), Options::default())
+        .await
+        .unwrap();
+    let got = SharedBuffer::default();
+    let options = Options {
+        normalize: Some(TimestampConflict::KeepFirst),
+        ..Options::default()
+    };
+    let summary = estimate_fare(shuffled.as_bytes(), got.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        NormalizationReport {
+            reordered: 2,
+            duplicates: 1,
+            conflicts: 1,
+        },
+        summary.normalized
+    );
+    assert_eq!(want.contents(), got.contents());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_as_json() {
+    let input = "1,37.966660,23.728308,1405594957\n2,37.966627,23.728263,1405594966\n";
+    let output = SharedBuffer::default();
+    let options = Options {
+        output_format: OutputFormat::JsonArray,
+        amount_format: AmountFormat::MinorUnits,
+        ..Options::default()
+    };
+
+    estimate_fare(input.as_bytes(), output.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        "[\n{\"id_ride\":1,\"fare_estimate\":347},\n{\"id_ride\":2,\"fare_estimate\":347}\n]\n",
+        output.contents()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_with_timestamp_formats() {
+    // Fixes a quarter of a second apart, 5m away: 72 km/h, so not too fast.
+    let seconds = "1,37.966660,23.728308,1405594957.00\n1,37.966660,23.728365,1405594957.25\n";
+    let milliseconds = "1,37.966660,23.728308,1405594957000\n1,37.966660,23.728365,1405594957250\n";
+    let rfc3339 = "1,37.966660,23.728308,2014-07-17T14:02:37+03:00\n\
+                   1,37.966660,23.728365,2014-07-17T11:02:37.250Z\n";
+
+    for (input, timestamp_format) in [
+        (seconds, TimestampFormat::Seconds),
+        (milliseconds, TimestampFormat::Milliseconds),
+        (rfc3339, TimestampFormat::Rfc3339),
+        (milliseconds, TimestampFormat::Auto),
+        (rfc3339, TimestampFormat::Auto),
+    ] {
+        let (segments_tx, segments_rx) = std::sync::mpsc::channel();
+        let options = Options {
+            timestamp_format,
+            audit: Some(Box::new(move |segment| segments_tx.send(segment).unwrap())),
+            ..Options::default()
+        };
+
+        let summary = estimate_fare(input.as_bytes(), io::sink(), options)
+            .await
+            .unwrap();
+
+        assert_eq!(0, summary.rejected_rows, "{:?}", timestamp_format);
+        let segment = segments_rx.recv().unwrap();
+        assert_eq!("2014-07-17T11:02:37.250Z", segment.end);
+        assert_eq!(Classification::Day, segment.classification);
+        assert!((segment.speed_kmh - 72.0).abs() < 1.0);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_rejects_invalid_timestamps() {
+    let input = "1,37.966660,23.728308,2014-07-17T11:02:37Z\n1,37.966660,23.728365,yesterday\n";
+    let (rejections_tx, rejections_rx) = std::sync::mpsc::channel();
+    let options = Options {
+        timestamp_format: TimestampFormat::Seconds,
+        errors: Box::new(move |rejection| rejections_tx.send(rejection).unwrap()),
+        ..Options::default()
+    };
+
+    estimate_fare(input.as_bytes(), io::sink(), options)
+        .await
+        .unwrap();
+
+    let reasons: Vec<_> = rejections_rx
+        .into_iter()
+        .map(|rejection| (rejection.kind, rejection.line))
+        .collect();
+    assert_eq!(
+        vec![
+            ("invalid_timestamp", Some(1)),
+            ("invalid_timestamp", Some(2))
+        ],
+        reasons
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn estimate_fare_empty_input() {
+    let output = SharedBuffer::default();
+
+    let summary = estimate_fare(io::empty(), output.clone(), Options::default())
+        .await
+        .unwrap();
+
+    assert_eq!(Summary::default(), summary);
+    assert_eq!("", output.contents());
+}
+
+#[test]
+fn reorder_buffer() {
+    let mut buffer = ReorderBuffer::new();
+
+    buffer.push(1, "b");
+    buffer.push(2, "c");
+    assert_eq!(None, buffer.pop_ready());
+
+    buffer.push(0, "a");
+    assert_eq!(Some("a"), buffer.pop_ready());
+    assert_eq!(Some("b"), buffer.pop_ready());
+    assert_eq!(Some("c"), buffer.pop_ready());
+    assert_eq!(None, buffer.pop_ready());
+
+    buffer.push(4, "e");
+    assert_eq!(None, buffer.pop_ready());
+    buffer.push(3, "d");
+    assert_eq!(Some("d"), buffer.pop_ready());
+    assert_eq!(Some("e"), buffer.pop_ready());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn estimate_fare_preserves_input_order() {
+    // Long rides come first, so they tend to finish after the short ones.
+    let mut input = String::new();
+    for id in 0..200 {
+        let positions = if id < 100 { 500 - id * 5 } else { 1 };
+        for i in 0..positions {
+            input += &format!("{},37.96{:04},23.72,{}\n", id, i, 1405594957 + i * 10);
+        }
+    }
+    let output = SharedBuffer::default();
+
+    estimate_fare(io::Cursor::new(input), output.clone(), Options::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<u32> = output
+        .contents()
+        .lines()
+        .map(|line| line.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!((0..200).collect::<Vec<u32>>(), ids);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn calculate_all_fares_caps_fares_in_flight() {
+    let (rides_tx, rides_rx) = mpsc::channel(100);
+    for id in 0..100 {
+        rides_tx
+            .send(Ok(Ride {
+                id,
+                positions: vec![],
+            }))
+            .await
+            .unwrap();
+    }
+    drop(rides_tx);
+
+    let (fares_tx, mut fares_rx) = mpsc::channel(100);
+    let calculation = tokio::spawn(calculate_all_fares(
+        rides_rx,
+        fares_tx,
+        Arc::new(Tariff::default()),
+        Options::default().errors,
+        None,
+        3,
+        false,
+    ));
+
+    // Nothing is written, so no slot is ever given back.
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let mut unwritten = vec![];
+    while let Ok(calculated) = fares_rx.try_recv() {
+        unwritten.push(calculated);
+    }
+    assert_eq!(3, unwritten.len());
+
+    drop(unwritten);
+    let mut written = 0;
+    while fares_rx.recv().await.is_some() {
+        written += 1;
+    }
+    assert_eq!(97, written);
+    assert_eq!(100, calculation.await.unwrap().unwrap().rides);
+}
+
+// Runs on a single-threaded runtime, which the blocking reader and writer
+// must leave free for the pricing.
+#[tokio::test]
+async fn estimate_fare_with_minimal_buffers() {
+    let input = "1,37.966660,23.728308,1405594957\n\
+                 2,37.966627,23.728263,1405594966\n\
+                 3,37.966627,23.728263,1405594966\n";
+    let output = SharedBuffer::default();
+    let options = Options {
+        channel_capacity: 1,
+        max_in_flight: 1,
+        ..Options::default()
+    };
+
+    estimate_fare(input.as_bytes(), output.clone(), options)
+        .await
+        .unwrap();
+
+    assert_eq!("1,3.47\n2,3.47\n3,3.47\n", output.contents());
+}
+
+#[tokio::test]
+async fn estimate_fare_with_more_rides_in_flight_than_buffered() {
+    let paths = std::fs::File::open("paths.csv").unwrap();
+    let output = SharedBuffer::default();
+    let options = Options {
+        channel_capacity: 1,
+        max_in_flight: 8,
+        ..Options::default()
+    };
+
+    let summary = estimate_fare(paths, output.clone(), options).await.unwrap();
+
+    assert_eq!(summary.rides, output.contents().lines().count());
+    assert!(output.contents().starts_with("1,11.34\n2,13.10\n"));
+}
+
+#[tokio::test]
+async fn estimate_fare_refuses_empty_buffers() {
+    for options in [
+        Options {
+            channel_capacity: 0,
+            ..Options::default()
+        },
+        Options {
+            max_in_flight: 0,
+            ..Options::default()
+        },
+    ] {
+        let result = estimate_fare(io::empty(), io::sink(), options).await;
+        assert!(matches!(result, Err(MainError::OptionError(..))));
+    }
+}
+
+// An in-memory output that can be inspected once `estimate_fare` owned it.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
@@ -586,6 +2209,7 @@ fn it_keeps_good_segments() {
                     latitude: 38.898556,
                     longitude: -77.037852,
                 },
+                accuracy: None,
             },
             Position {
                 datetime: Utc.ymd(2020, 10, 20).and_hms(0, 1, 0),
@@ -593,6 +2217,7 @@ fn it_keeps_good_segments() {
                     latitude: 38.897147,
                     longitude: -77.043934,
                 }, // ± 0.55km from previous position, ± 33 km/h
+                accuracy: None,
             },
             Position {
                 datetime: Utc.ymd(2020, 10, 20).and_hms(0, 2, 0),
@@ -600,11 +2225,12 @@ fn it_keeps_good_segments() {
                     latitude: 38.898556,
                     longitude: -77.037852,
                 }, // ± 0.55km from previous position, ± 33 km/h
+                accuracy: None,
             },
         ],
     };
 
-    let segments = get_good_segments(&ride);
+    let segments = ride.segments(&Tariff::default());
     assert_eq!(2, segments.len(),);
 }
 
@@ -623,6 +2249,7 @@ mod good_segment_tests {
                         latitude: 38.898556,
                         longitude: -77.037852,
                     },
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(0, 1, 0),
@@ -630,6 +2257,7 @@ mod good_segment_tests {
                         latitude: 39.897147,
                         longitude: -77.043934,
                     }, // ± 111km from previous position, ± 6672 km/h
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(0, 2, 0),
@@ -637,11 +2265,12 @@ mod good_segment_tests {
                         latitude: 40.898556,
                         longitude: -77.037852,
                     },
+                    accuracy: None,
                 },
             ],
         };
 
-        let segments = get_good_segments(&ride);
+        let segments = ride.segments(&Tariff::default());
         assert_eq!(0, segments.len(),);
     }
 
@@ -656,6 +2285,7 @@ mod good_segment_tests {
                         latitude: 38.898556,
                         longitude: -77.037852,
                     },
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(0, 0, 30),
@@ -663,6 +2293,7 @@ mod good_segment_tests {
                         latitude: 39.897147,
                         longitude: -77.043934,
                     }, // ± 111km from previous position, ± 6672 km/h
+                    accuracy: None,
                 },
                 Position {
                     datetime: Utc.ymd(2020, 10, 20).and_hms(0, 1, 0),
@@ -670,11 +2301,12 @@ mod good_segment_tests {
                         latitude: 38.897147,
                         longitude: -77.043934,
                     }, // ± 0.55km from position 1, ± 33 km/h
+                    accuracy: None,
                 },
             ],
         };
 
-        let segments = get_good_segments(&ride);
+        let segments = ride.segments(&Tariff::default());
         assert_eq!(1, segments.len(),);
     }
 }
@@ -0,0 +1,426 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// Fares are accumulated in millionths of a currency unit, then rounded to
+// whole minor units (e.g. cents) or to the cash increment.
+const MICROS_PER_UNIT: i64 = 1_000_000;
+const MICROS_PER_CASH_INCREMENT: i64 = 50_000;
+const MAX_DECIMALS: usize = 6;
+
+// A tariff's rate or fee, held exactly in millionths of a currency unit. In
+// tariff files it's a number or a decimal string, e.g. 0.74 or "0.74", with
+// at most six decimal places.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Price(i64);
+
+impl Price {
+    pub const fn from_micros(micros: i64) -> Self {
+        Price(micros)
+    }
+
+    pub fn micros(self) -> i64 {
+        self.0
+    }
+
+    // The price of `quantity` units, e.g. km or hours, to the nearest micro.
+    pub fn times(self, quantity: f64) -> i64 {
+        (self.0 as f64 * quantity).round() as i64
+    }
+}
+
+impl FromStr for Price {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid price {:?}", text);
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let (units, decimals) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if units.is_empty() || !all_digits(units) || !all_digits(decimals) {
+            return Err(invalid());
+        }
+        if decimals.len() > MAX_DECIMALS {
+            return Err(format!("{:?} has more than six decimal places", text));
+        }
+
+        let units: i64 = units.parse().map_err(|_| invalid())?;
+        let decimals: i64 = format!("{:0<6}", decimals).parse().unwrap();
+        let micros = units
+            .checked_mul(MICROS_PER_UNIT)
+            .and_then(|micros| micros.checked_add(decimals))
+            .ok_or_else(invalid)?;
+        Ok(Price(if negative { -micros } else { micros }))
+    }
+}
+
+impl<'de> Deserialize<'de> for Price {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(PriceVisitor)
+    }
+}
+
+struct PriceVisitor;
+
+impl<'de> de::Visitor<'de> for PriceVisitor {
+    type Value = Price;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Price, E> {
+        text.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, units: i64) -> Result<Price, E> {
+        self.visit_str(&units.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, units: u64) -> Result<Price, E> {
+        self.visit_str(&units.to_string())
+    }
+
+    // A number's shortest decimal form is what was written, e.g. 0.74 rather
+    // than 0.7399999999999999911182158029987.
+    fn visit_f64<E: de::Error>(self, number: f64) -> Result<Price, E> {
+        if !number.is_finite() {
+            return Err(E::custom(format!("invalid price {}", number)));
+        }
+        self.visit_str(&number.to_string())
+    }
+}
+
+// ISO 4217 currency code, e.g. `EUR`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const EUR: Currency = Currency(*b"EUR");
+
+    pub fn code(&self) -> &str {
+        // Only ever built from ASCII letters.
+        std::str::from_utf8(&self.0).unwrap()
+    }
+
+    // Decimal places of the currency's minor unit, as listed in ISO 4217.
+    // Codes it doesn't list are taken to have two.
+    pub fn minor_digits(&self) -> u32 {
+        match self.code() {
+            "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF"
+            | "UGX" | "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+            "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+            "CLF" | "UYW" => 4,
+            _ => 2,
+        }
+    }
+
+    fn micros_per_minor_unit(&self) -> i64 {
+        10_i64.pow(MAX_DECIMALS as u32 - self.minor_digits())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code.as_bytes() {
+            &[a, b, c] if code.bytes().all(|byte| byte.is_ascii_uppercase()) => {
+                Ok(Currency([a, b, c]))
+            }
+            _ => Err(format!("invalid currency code {:?}", code)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(de::Error::custom)
+    }
+}
+
+// Half-up rounds ties away from zero, to the currency's minor unit. Cash
+// rounds to the nearest 0.05, ties away from zero, so it needs a currency
+// with at least two decimal places.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    HalfUp,
+    HalfEven,
+    Cash,
+}
+
+impl Rounding {
+    pub fn round(self, micros: i64, currency: Currency) -> i64 {
+        let increment = match self {
+            Rounding::HalfUp | Rounding::HalfEven => currency.micros_per_minor_unit(),
+            Rounding::Cash => MICROS_PER_CASH_INCREMENT,
+        };
+        let quotient = micros.div_euclid(increment);
+        let twice_remainder = 2 * micros.rem_euclid(increment);
+
+        let round_up = match self {
+            Rounding::HalfUp | Rounding::Cash => {
+                twice_remainder > increment || (twice_remainder == increment && micros >= 0)
+            }
+            Rounding::HalfEven => {
+                twice_remainder > increment
+                    || (twice_remainder == increment && quotient.rem_euclid(2) == 1)
+            }
+        };
+
+        (quotient + round_up as i64) * increment
+    }
+}
+
+// Whether the flag and each segment's day, night and idle charges are rounded
+// before being added up, or only the total.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingPoint {
+    Segment,
+    Total,
+}
+
+// Serialized as a decimal string with the currency's decimal places, e.g.
+// "12.30" in EUR or "1230" in JPY.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Amount {
+    minor_units: i64,
+    currency: Currency,
+}
+
+impl Amount {
+    pub fn new(minor_units: i64, currency: Currency) -> Self {
+        Amount {
+            minor_units,
+            currency,
+        }
+    }
+
+    // Any fraction of a minor unit is truncated: round `micros` first.
+    pub fn from_micros(micros: i64, currency: Currency) -> Self {
+        Amount::new(micros / currency.micros_per_minor_unit(), currency)
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.minor_units < 0 { "-" } else { "" };
+        let minor_units = self.minor_units.abs();
+        let digits = self.currency.minor_digits() as usize;
+        if digits == 0 {
+            return write!(f, "{}{}", sign, minor_units);
+        }
+
+        let per_unit = 10_i64.pow(digits as u32);
+        write!(
+            f,
+            "{}{}.{:0digits$}",
+            sign,
+            minor_units / per_unit,
+            minor_units % per_unit,
+            digits = digits
+        )
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+// How amounts are written out: as a decimal string like "12.30", or as a
+// whole number of minor units like 1230.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AmountFormat {
+    #[default]
+    Decimal,
+    MinorUnits,
+}
+
+impl FromStr for AmountFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "decimal" => Ok(AmountFormat::Decimal),
+            "minor-units" => Ok(AmountFormat::MinorUnits),
+            _ => Err(format!("unknown amount format {:?}", name)),
+        }
+    }
+}
+
+// An amount that serializes as `format` says.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FormattedAmount {
+    amount: Amount,
+    format: AmountFormat,
+}
+
+impl FormattedAmount {
+    pub fn new(amount: Amount, format: AmountFormat) -> Self {
+        FormattedAmount { amount, format }
+    }
+}
+
+impl Serialize for FormattedAmount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.format {
+            AmountFormat::Decimal => self.amount.serialize(serializer),
+            AmountFormat::MinorUnits => serializer.serialize_i64(self.amount.minor_units),
+        }
+    }
+}
+
+#[test]
+fn prices_are_exact() {
+    for (text, micros) in [
+        ("3.47", 3_470_000),
+        ("0.74", 740_000),
+        ("11.9", 11_900_000),
+        ("4", 4_000_000),
+        ("0.000001", 1),
+        ("-1.20", -1_200_000),
+    ] {
+        assert_eq!(Ok(Price::from_micros(micros)), text.parse(), "{}", text);
+    }
+    for text in [
+        "",
+        ".5",
+        "1.2.3",
+        "1e3",
+        "0.0000001",
+        "€3",
+        "99999999999999",
+    ] {
+        assert!(text.parse::<Price>().is_err(), "{}", text);
+    }
+
+    // Numbers in tariff files as written, not as the nearest f64.
+    let prices: Vec<Price> = serde_json::from_str("[0.74, 1.3, 3, \"0.10\"]").unwrap();
+    assert_eq!(
+        vec![740_000, 1_300_000, 3_000_000, 100_000],
+        prices
+            .iter()
+            .map(|price| price.micros())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(1_190_000, Price::from_micros(11_900_000).times(0.1));
+}
+
+#[test]
+fn rounding_modes() {
+    for (micros, half_up, half_even, cash) in [
+        (1_234_999, 1_230_000, 1_230_000, 1_250_000),
+        (1_235_000, 1_240_000, 1_240_000, 1_250_000),
+        (1_245_000, 1_250_000, 1_240_000, 1_250_000),
+        (1_225_000, 1_230_000, 1_220_000, 1_250_000),
+        (1_224_999, 1_220_000, 1_220_000, 1_200_000),
+        (1_275_000, 1_280_000, 1_280_000, 1_300_000),
+        (1_270_000, 1_270_000, 1_270_000, 1_250_000),
+        (-1_235_000, -1_240_000, -1_240_000, -1_250_000),
+        (-1_225_000, -1_230_000, -1_220_000, -1_250_000),
+        (0, 0, 0, 0),
+    ] {
+        let round = |rounding: Rounding| rounding.round(micros, Currency::EUR);
+        assert_eq!(half_up, round(Rounding::HalfUp), "{}", micros);
+        assert_eq!(half_even, round(Rounding::HalfEven), "{}", micros);
+        assert_eq!(cash, round(Rounding::Cash), "{}", micros);
+    }
+}
+
+#[test]
+fn rounding_to_other_minor_units() {
+    let (jpy, bhd) = ("JPY".parse().unwrap(), "BHD".parse().unwrap());
+
+    assert_eq!(1_000_000, Rounding::HalfUp.round(1_499_999, jpy));
+    assert_eq!(2_000_000, Rounding::HalfEven.round(1_500_000, jpy));
+    assert_eq!(1_235_000, Rounding::HalfUp.round(1_234_500, bhd));
+    assert_eq!(1_250_000, Rounding::Cash.round(1_234_500, bhd));
+}
+
+#[test]
+fn currency_codes() {
+    assert_eq!(Ok(Currency::EUR), "EUR".parse());
+    assert_eq!("CHF", "CHF".parse::<Currency>().unwrap().to_string());
+    for code in ["eur", "EURO", "E1R", ""] {
+        assert!(code.parse::<Currency>().is_err(), "{}", code);
+    }
+}
+
+#[test]
+fn amount_display() {
+    for (minor_units, want) in [
+        (0, "0.00"),
+        (5, "0.05"),
+        (347, "3.47"),
+        (17785, "177.85"),
+        (-120, "-1.20"),
+    ] {
+        assert_eq!(want, Amount::new(minor_units, Currency::EUR).to_string());
+    }
+    assert_eq!(
+        Amount::new(1231, Currency::EUR),
+        Amount::from_micros(12_310_000, Currency::EUR)
+    );
+
+    let (jpy, bhd) = ("JPY".parse().unwrap(), "BHD".parse().unwrap());
+    assert_eq!("1230", Amount::from_micros(1_230_000_000, jpy).to_string());
+    assert_eq!("-7", Amount::new(-7, jpy).to_string());
+    assert_eq!("12.305", Amount::from_micros(12_305_000, bhd).to_string());
+    assert_eq!("0.050", Amount::new(50, bhd).to_string());
+}
+
+#[test]
+fn formatted_amounts() {
+    let amount = Amount::new(1230, Currency::EUR);
+
+    assert_eq!(
+        "\"12.30\"",
+        serde_json::to_string(&FormattedAmount::new(amount, AmountFormat::Decimal)).unwrap()
+    );
+    assert_eq!(
+        "1230",
+        serde_json::to_string(&FormattedAmount::new(amount, AmountFormat::MinorUnits)).unwrap()
+    );
+    assert_eq!(Ok(AmountFormat::MinorUnits), "minor-units".parse());
+    assert!("cents".parse::<AmountFormat>().is_err());
+}
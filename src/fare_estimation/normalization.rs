@@ -0,0 +1,244 @@
+use std::ops::AddAssign;
+use std::str::FromStr;
+
+use super::{haversine, Position};
+
+// What to do with fixes that share a timestamp but not a location, once exact
+// duplicates are gone. `KeepFirst` and `KeepLast` go by input order,
+// `Average` takes the mean of their coordinates, and `DropAll` drops them
+// all.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestampConflict {
+    #[default]
+    KeepFirst,
+    KeepLast,
+    Average,
+    DropAll,
+}
+
+impl FromStr for TimestampConflict {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keep-first" => Ok(TimestampConflict::KeepFirst),
+            "keep-last" => Ok(TimestampConflict::KeepLast),
+            "average" => Ok(TimestampConflict::Average),
+            "drop-all" => Ok(TimestampConflict::DropAll),
+            _ => Err(format!("unknown timestamp conflict policy {:?}", name)),
+        }
+    }
+}
+
+// What normalizing changed. `reordered` counts positions that came after a
+// later one, `duplicates` exact repeats that were dropped, and `conflicts`
+// timestamps shared by different locations, each resolved by the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NormalizationReport {
+    pub reordered: usize,
+    pub duplicates: usize,
+    pub conflicts: usize,
+}
+
+impl NormalizationReport {
+    pub fn is_empty(&self) -> bool {
+        *self == NormalizationReport::default()
+    }
+}
+
+impl AddAssign for NormalizationReport {
+    fn add_assign(&mut self, other: Self) {
+        self.reordered += other.reordered;
+        self.duplicates += other.duplicates;
+        self.conflicts += other.conflicts;
+    }
+}
+
+// Orders a ride's positions by timestamp, so that every segment runs forward
+// in time, and leaves at most one position per timestamp.
+pub(super) fn normalize(
+    positions: &mut Vec<Position>,
+    conflicts: TimestampConflict,
+) -> NormalizationReport {
+    let mut report = NormalizationReport::default();
+
+    let mut latest = None;
+    for position in positions.iter() {
+        match latest {
+            Some(latest) if position.datetime < latest => report.reordered += 1,
+            _ => latest = Some(position.datetime),
+        }
+    }
+    if report.reordered > 0 {
+        // Stable, so fixes sharing a timestamp stay in input order.
+        positions.sort_by_key(|position| position.datetime);
+    }
+
+    let mut normalized = Vec::with_capacity(positions.len());
+    let mut same_time: Vec<Position> = vec![];
+    for position in positions.drain(..) {
+        if let Some(first) = same_time.first() {
+            if first.datetime != position.datetime {
+                resolve(&mut same_time, conflicts, &mut report, &mut normalized);
+            }
+        }
+        if same_time.iter().any(|fix| same_location(fix, &position)) {
+            report.duplicates += 1;
+        } else {
+            same_time.push(position);
+        }
+    }
+    resolve(&mut same_time, conflicts, &mut report, &mut normalized);
+
+    *positions = normalized;
+    report
+}
+
+// Moves the distinct fixes of one timestamp into `normalized`, as one.
+fn resolve(
+    same_time: &mut Vec<Position>,
+    conflicts: TimestampConflict,
+    report: &mut NormalizationReport,
+    normalized: &mut Vec<Position>,
+) {
+    if same_time.len() > 1 {
+        report.conflicts += 1;
+    }
+
+    let resolved = match (same_time.len(), conflicts) {
+        (0, _) => None,
+        (1, _) | (_, TimestampConflict::KeepFirst) => same_time.first().cloned(),
+        (_, TimestampConflict::KeepLast) => same_time.last().cloned(),
+        (count, TimestampConflict::Average) => Some(Position {
+            datetime: same_time[0].datetime,
+            location: haversine::Location {
+                latitude: same_time
+                    .iter()
+                    .map(|fix| fix.location.latitude)
+                    .sum::<f64>()
+                    / count as f64,
+                longitude: same_time
+                    .iter()
+                    .map(|fix| fix.location.longitude)
+                    .sum::<f64>()
+                    / count as f64,
+            },
+            // The worst of them, if all are known.
+            accuracy: same_time
+                .iter()
+                .map(|fix| fix.accuracy)
+                .try_fold(0.0, |worst: f64, accuracy| Some(worst.max(accuracy?))),
+        }),
+        (_, TimestampConflict::DropAll) => None,
+    };
+
+    normalized.extend(resolved);
+    same_time.clear();
+}
+
+fn same_location(a: &Position, b: &Position) -> bool {
+    a.location.latitude == b.location.latitude && a.location.longitude == b.location.longitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn positions(fixes: &[(i64, f64)]) -> Vec<Position> {
+        fixes
+            .iter()
+            .map(|&(timestamp, latitude)| Position {
+                datetime: Utc.timestamp(timestamp, 0),
+                location: haversine::Location {
+                    latitude,
+                    longitude: 23.72,
+                },
+                accuracy: None,
+            })
+            .collect()
+    }
+
+    fn fixes(positions: &[Position]) -> Vec<(i64, f64)> {
+        positions
+            .iter()
+            .map(|position| (position.datetime.timestamp(), position.location.latitude))
+            .collect()
+    }
+
+    #[test]
+    fn it_leaves_ordered_positions_alone() {
+        let mut ride = positions(&[(10, 37.96), (20, 37.97), (30, 37.98)]);
+        let report = normalize(&mut ride, TimestampConflict::default());
+
+        assert!(report.is_empty());
+        assert_eq!(vec![(10, 37.96), (20, 37.97), (30, 37.98)], fixes(&ride));
+    }
+
+    #[test]
+    fn it_orders_and_deduplicates_positions() {
+        let mut ride = positions(&[
+            (10, 37.96),
+            (30, 37.98),
+            (20, 37.97),
+            (30, 37.98),
+            (10, 37.96),
+        ]);
+        let report = normalize(&mut ride, TimestampConflict::default());
+
+        assert_eq!(
+            NormalizationReport {
+                reordered: 2,
+                duplicates: 2,
+                conflicts: 0,
+            },
+            report
+        );
+        assert_eq!(vec![(10, 37.96), (20, 37.97), (30, 37.98)], fixes(&ride));
+    }
+
+    #[test]
+    fn it_resolves_timestamp_conflicts() {
+        let input = [
+            (10, 37.96),
+            (20, 37.97),
+            (20, 37.99),
+            (20, 37.97),
+            (30, 37.98),
+        ];
+
+        for (conflicts, want) in [
+            (
+                TimestampConflict::KeepFirst,
+                vec![(10, 37.96), (20, 37.97), (30, 37.98)],
+            ),
+            (
+                TimestampConflict::KeepLast,
+                vec![(10, 37.96), (20, 37.99), (30, 37.98)],
+            ),
+            (
+                TimestampConflict::Average,
+                vec![(10, 37.96), (20, 37.98), (30, 37.98)],
+            ),
+            (TimestampConflict::DropAll, vec![(10, 37.96), (30, 37.98)]),
+        ] {
+            let mut ride = positions(&input);
+            let report = normalize(&mut ride, conflicts);
+
+            assert_eq!(
+                NormalizationReport {
+                    reordered: 0,
+                    duplicates: 1,
+                    conflicts: 1,
+                },
+                report
+            );
+            let got = fixes(&ride);
+            assert_eq!(want.len(), got.len(), "{:?}", conflicts);
+            for ((want_time, want_lat), (got_time, got_lat)) in want.into_iter().zip(got) {
+                assert_eq!(want_time, got_time, "{:?}", conflicts);
+                assert!((want_lat - got_lat).abs() < 1e-9, "{:?}", conflicts);
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use super::breakdown::DetailedFare;
+use super::money::{AmountFormat, FormattedAmount};
+use super::Fare;
+
+// How fares are written out.
+//
+// `Csv` is headerless `id,fare` rows, unless the breakdown is asked for.
+// `JsonLines` is one object per fare, e.g. `{"id_ride":1,"fare_estimate":"3.47"}`,
+// and `JsonArray` the same objects in a single array.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    JsonLines,
+    JsonArray,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "csv" => Ok(OutputFormat::Csv),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "json" => Ok(OutputFormat::JsonArray),
+            _ => Err(format!("unknown output format {:?}", name)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FareRow {
+    id_ride: u32,
+    fare_estimate: FormattedAmount,
+}
+
+enum Writer<W: io::Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(io::BufWriter<W>),
+    JsonArray(io::BufWriter<W>),
+}
+
+pub(super) struct FareWriter<W: io::Write> {
+    writer: Writer<W>,
+    amount_format: AmountFormat,
+    breakdown: bool,
+    written: usize,
+}
+
+impl<W: io::Write> FareWriter<W> {
+    pub(super) fn new(
+        output: W,
+        format: OutputFormat,
+        amount_format: AmountFormat,
+        breakdown: bool,
+    ) -> Self {
+        let writer = match format {
+            OutputFormat::Csv => Writer::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(breakdown)
+                    .from_writer(output),
+            )),
+            OutputFormat::JsonLines => Writer::JsonLines(io::BufWriter::new(output)),
+            OutputFormat::JsonArray => Writer::JsonArray(io::BufWriter::new(output)),
+        };
+
+        FareWriter {
+            writer,
+            amount_format,
+            breakdown,
+            written: 0,
+        }
+    }
+
+    pub(super) fn write(&mut self, fare: &Fare) -> io::Result<()> {
+        if self.breakdown {
+            self.write_row(&DetailedFare::new(
+                fare.id,
+                fare.amount,
+                &fare.breakdown,
+                self.amount_format,
+            ))
+        } else {
+            self.write_row(&FareRow {
+                id_ride: fare.id,
+                fare_estimate: FormattedAmount::new(fare.amount, self.amount_format),
+            })
+        }
+    }
+
+    fn write_row(&mut self, row: &impl Serialize) -> io::Result<()> {
+        match &mut self.writer {
+            Writer::Csv(writer) => writer.serialize(row)?,
+            Writer::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writer.write_all(b"\n")?;
+            }
+            Writer::JsonArray(writer) => {
+                writer.write_all(if self.written == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *writer, row)?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    // Closes the JSON array, if any, and flushes.
+    pub(super) fn finish(mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Writer::Csv(writer) => writer.flush(),
+            Writer::JsonLines(writer) => writer.flush(),
+            Writer::JsonArray(writer) => {
+                writer.write_all(if self.written == 0 { b"[]\n" } else { b"\n]\n" })?;
+                writer.flush()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Amount, Currency, FareBreakdown};
+    use super::*;
+
+    fn write(format: OutputFormat, amount_format: AmountFormat, fares: &[(u32, i64)]) -> String {
+        let mut output = vec![];
+        let mut writer = FareWriter::new(&mut output, format, amount_format, false);
+        for &(id, minor_units) in fares {
+            writer
+                .write(&Fare {
+                    id,
+                    amount: Amount::new(minor_units, Currency::EUR),
+                    breakdown: FareBreakdown::new(Currency::EUR),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn it_writes_each_format() {
+        let fares = [(1, 347), (2, 17785)];
+
+        assert_eq!(
+            "1,3.47\n2,177.85\n",
+            write(OutputFormat::Csv, AmountFormat::Decimal, &fares)
+        );
+        assert_eq!(
+            "1,347\n2,17785\n",
+            write(OutputFormat::Csv, AmountFormat::MinorUnits, &fares)
+        );
+        assert_eq!(
+            "{\"id_ride\":1,\"fare_estimate\":\"3.47\"}\n\
+             {\"id_ride\":2,\"fare_estimate\":\"177.85\"}\n",
+            write(OutputFormat::JsonLines, AmountFormat::Decimal, &fares)
+        );
+        assert_eq!(
+            "[\n{\"id_ride\":1,\"fare_estimate\":347},\n\
+             {\"id_ride\":2,\"fare_estimate\":17785}\n]\n",
+            write(OutputFormat::JsonArray, AmountFormat::MinorUnits, &fares)
+        );
+    }
+
+    #[test]
+    fn it_writes_an_empty_json_array() {
+        assert_eq!(
+            "[]\n",
+            write(OutputFormat::JsonArray, AmountFormat::Decimal, &[])
+        );
+        assert_eq!(
+            "",
+            write(OutputFormat::JsonLines, AmountFormat::Decimal, &[])
+        );
+    }
+
+    #[test]
+    fn it_writes_the_breakdown_as_json() {
+        let mut output = vec![];
+        let mut writer = FareWriter::new(
+            &mut output,
+            OutputFormat::JsonLines,
+            AmountFormat::MinorUnits,
+            true,
+        );
+        writer
+            .write(&Fare {
+                id: 1,
+                amount: Amount::new(347, Currency::EUR),
+                breakdown: FareBreakdown {
+                    flag: Amount::new(130, Currency::EUR),
+                    minimum_applied: true,
+                    ..FareBreakdown::new(Currency::EUR)
+                },
+            })
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            "{\"id_ride\":1,\"fare_estimate\":347,\"flag\":130,\"day_km\":0.0,\"day_amount\":0,\
+             \"night_km\":0.0,\"night_amount\":0,\"idle_seconds\":0.0,\"idle_amount\":0,\
+             \"discarded_segments\":0,\"minimum_applied\":true}\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
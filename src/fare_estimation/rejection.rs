@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::io;
+
+use super::ReadError;
+
+// An input row that was left out of the fares, as reported to an `ErrorSink`.
+// `line` and `byte` are unknown for errors that aren't tied to a single row.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rejection {
+    pub line: Option<u64>,
+    pub byte: Option<u64>,
+    pub kind: &'static str,
+    pub reason: String,
+    pub raw: Option<String>,
+}
+
+impl From<&ReadError> for Rejection {
+    fn from(error: &ReadError) -> Self {
+        match error {
+            ReadError::RowError {
+                line,
+                byte,
+                raw,
+                error,
+            } => Rejection {
+                line: Some(*line),
+                byte: Some(*byte),
+                raw: Some(raw.clone()),
+                ..Rejection::from(error.as_ref())
+            },
+            error => Rejection {
+                line: None,
+                byte: None,
+                kind: error.kind(),
+                reason: error.to_string(),
+                raw: None,
+            },
+        }
+    }
+}
+
+pub trait ErrorSink: Send {
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()>;
+}
+
+impl<F> ErrorSink for F
+where
+    F: FnMut(Rejection) + Send,
+{
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()> {
+        self(rejection);
+        Ok(())
+    }
+}
+
+// Writes rejections as CSV, with a `line,byte,kind,reason,raw` header.
+pub struct CsvErrorSink<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> CsvErrorSink<W> {
+    pub fn new(output: W) -> Self {
+        CsvErrorSink {
+            writer: csv::Writer::from_writer(output),
+        }
+    }
+}
+
+impl<W: io::Write + Send> ErrorSink for CsvErrorSink<W> {
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()> {
+        self.writer.serialize(rejection)?;
+        self.writer.flush()
+    }
+}
+
+#[test]
+fn rejection_from_row_error() {
+    let error = ReadError::RowError {
+        line: 3,
+        byte: 50,
+        raw: ",37.96,23.72,1405594970".to_string(),
+        error: Box::new(ReadError::MissingValueError {
+            field: "id".to_string(),
+        }),
+    };
+
+    assert_eq!(
+        Rejection {
+            line: Some(3),
+            byte: Some(50),
+            kind: "missing_value",
+            reason: "missing value for id".to_string(),
+            raw: Some(",37.96,23.72,1405594970".to_string()),
+        },
+        Rejection::from(&error)
+    );
+}
+
+#[test]
+fn csv_error_sink() {
+    let mut output = vec![];
+    {
+        let mut sink = CsvErrorSink::new(&mut output);
+        sink.reject(Rejection {
+            line: Some(4),
+            byte: Some(74),
+            kind: "field_count",
+            reason: "expected 4 fields, found 3".to_string(),
+            raw: Some("1,37.96,23.72".to_string()),
+        })
+        .unwrap();
+    }
+
+    assert_eq!(
+        "line,byte,kind,reason,raw\n4,74,field_count,\"expected 4 fields, found 3\",\"1,37.96,23.72\"\n",
+        String::from_utf8(output).unwrap()
+    );
+}
@@ -0,0 +1,216 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::breakdown::DetailedFare;
+use super::input::parse_json_ride;
+use super::{estimate_fare, AmountFormat, MainError, Options, Tariff, TimestampFormat};
+
+// Chunks of a CSV request body read ahead of the fare calculations, and of
+// the response written ahead of the client.
+const CHUNKS_IN_FLIGHT: usize = 16;
+// The largest JSON ride `/fare` reads, in bytes.
+const MAX_RIDE_BYTES: usize = 4 * 1024 * 1024;
+
+// How the server reads positions and prices rides.
+#[derive(Default)]
+pub struct ServerOptions {
+    pub tariff: Tariff,
+    pub timestamp_format: TimestampFormat,
+    pub amount_format: AmountFormat,
+}
+
+// Serves fares over HTTP on `listener` until the server fails:
+//
+// - `POST /fare` takes one ride as JSON, e.g.
+//   `{"id_ride":1,"positions":[{"lat":37.96,"lng":23.72,"timestamp":1405594957}]}`,
+//   and answers with its fare and breakdown as a JSON object, or with a
+//   rejection and status 400 if the ride can't be priced. Rides over
+//   `MAX_RIDE_BYTES` get status 413.
+// - `POST /fares` takes positions as headerless CSV and streams back the
+//   fares as `estimate_fare` writes them. Unlike `estimate_fare`, it has
+//   nowhere to report rejected rows: rides are priced without them.
+pub async fn serve(listener: TcpListener, options: ServerOptions) -> Result<(), MainError> {
+    options.tariff.validate()?;
+    let options = Arc::new(options);
+
+    let make_service = make_service_fn(move |_| {
+        let options = options.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, options.clone()))) }
+    });
+
+    Server::from_tcp(listener)?.serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle(
+    request: Request<Body>,
+    options: Arc<ServerOptions>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::POST, "/fare") => price_ride(request.into_body(), &options).await,
+        (&Method::POST, "/fares") => estimate_fares(request.into_body(), &options),
+        (_, "/fare") | (_, "/fares") => status(StatusCode::METHOD_NOT_ALLOWED),
+        _ => status(StatusCode::NOT_FOUND),
+    };
+
+    Ok(response)
+}
+
+async fn price_ride(body: Body, options: &ServerOptions) -> Response<Body> {
+    let content = match read_ride(body).await {
+        Ok(content) => content,
+        Err(code) => return status(code),
+    };
+
+    match parse_json_ride(&content, options.timestamp_format, &options.tariff) {
+        Ok(ride) => {
+            let fare = ride.calculate_fare(&options.tariff);
+            let detailed = DetailedFare::new(
+                fare.id(),
+                fare.amount(),
+                fare.breakdown(),
+                options.amount_format,
+            );
+            json(StatusCode::OK, &detailed)
+        }
+        Err(rejection) => json(StatusCode::BAD_REQUEST, &rejection),
+    }
+}
+
+// Reads the whole body, unless it's longer than `MAX_RIDE_BYTES`, as its
+// Content-Length may already say.
+async fn read_ride(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    if body.size_hint().lower() > MAX_RIDE_BYTES as u64 {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let mut content = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if content.len() + chunk.len() > MAX_RIDE_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(content)
+}
+
+// Runs `estimate_fare` on the request body, streaming its output back.
+fn estimate_fares(mut body: Body, options: &ServerOptions) -> Response<Body> {
+    let (chunks_tx, chunks_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(io::Error::other);
+            if chunks_tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let (output_tx, mut output_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let failed_tx = output_tx.clone();
+    let estimate_options = Options {
+        tariff: options.tariff.clone(),
+        timestamp_format: options.timestamp_format,
+        amount_format: options.amount_format,
+        ..Options::default()
+    };
+    tokio::spawn(async move {
+        let input = BodyReader {
+            chunks: chunks_rx,
+            current: Bytes::new(),
+        };
+        let output = ChunkWriter { chunks: output_tx };
+        if estimate_fare(input, output, estimate_options)
+            .await
+            .is_err()
+        {
+            let _ = failed_tx.send(None).await;
+        }
+    });
+
+    let (mut response_tx, response) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = output_rx.recv().await {
+            match chunk {
+                Some(chunk) => {
+                    if response_tx.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                // Cut the response short, so the client can tell.
+                None => return response_tx.abort(),
+            }
+        }
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/csv")
+        .body(response)
+        .unwrap()
+}
+
+// Reads the request body, chunk by chunk, from `estimate_fare`'s blocking
+// reader.
+struct BodyReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl io::Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let length = buf.len().min(self.current.len());
+        buf[..length].copy_from_slice(&self.current.split_to(length));
+        Ok(length)
+    }
+}
+
+// Hands what's written to the response, chunk by chunk, from `estimate_fare`'s
+// blocking writer. `None` means the output failed.
+struct ChunkWriter {
+    chunks: mpsc::Sender<Option<Bytes>>,
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunks
+            .blocking_send(Some(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::{haversine, seconds_between, Position};
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+const METRES_PER_DEGREE: f64 = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+
+// How sure the filter is of a ride's speed before its second fix: a standard
+// deviation of 30 m/s, faster than any taxi.
+const INITIAL_SPEED_VARIANCE: f64 = 30.0 * 30.0;
+
+// Settings for smoothing positions with a constant-velocity Kalman filter.
+// `process_noise` is how much the speed may change, as the standard deviation
+// of acceleration in m/s², and `measurement_noise` how far off fixes are, as
+// the standard deviation of GPS error in metres. Lower process noise or
+// higher measurement noise smooths harder, and lags more behind turns.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Smoothing {
+    pub process_noise: f64,
+    pub measurement_noise: f64,
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Smoothing {
+            process_noise: 0.2,
+            measurement_noise: 10.0,
+        }
+    }
+}
+
+// Estimates each position from the fixes up to it, never from later ones, so
+// positions can be smoothed as they arrive. North and east are filtered
+// separately, in metres from the first fix.
+pub(super) struct KalmanFilter {
+    settings: Smoothing,
+    state: Option<State>,
+}
+
+struct State {
+    origin: haversine::Location,
+    metres_per_degree_longitude: f64,
+    datetime: DateTime<Utc>,
+    north: Axis,
+    east: Axis,
+}
+
+// Position and speed along one axis, with their covariance.
+struct Axis {
+    position: f64,
+    speed: f64,
+    covariance: [[f64; 2]; 2],
+}
+
+impl KalmanFilter {
+    pub(super) fn new(settings: Smoothing) -> Self {
+        KalmanFilter {
+            settings,
+            state: None,
+        }
+    }
+
+    pub(super) fn next(&mut self, position: &Position) -> Position {
+        let state = match &mut self.state {
+            Some(state) => state,
+            None => {
+                let variance = self.settings.measurement_noise.powi(2);
+                self.state = Some(State {
+                    origin: position.location.clone(),
+                    metres_per_degree_longitude: METRES_PER_DEGREE
+                        * position.location.latitude.to_radians().cos(),
+                    datetime: position.datetime,
+                    north: Axis::new(variance),
+                    east: Axis::new(variance),
+                });
+                return position.clone();
+            }
+        };
+
+        // Fixes out of order are taken as simultaneous.
+        let dt = seconds_between(&state.datetime, &position.datetime).max(0.0);
+        state.datetime = state.datetime.max(position.datetime);
+
+        let north = (position.location.latitude - state.origin.latitude) * METRES_PER_DEGREE;
+        let east = (position.location.longitude - state.origin.longitude)
+            * state.metres_per_degree_longitude;
+        state.north.step(north, dt, &self.settings);
+        state.east.step(east, dt, &self.settings);
+
+        let longitude = match state.metres_per_degree_longitude {
+            scale if scale > 0.0 => state.origin.longitude + state.east.position / scale,
+            _ => position.location.longitude,
+        };
+        Position {
+            datetime: position.datetime,
+            location: haversine::Location {
+                latitude: state.origin.latitude + state.north.position / METRES_PER_DEGREE,
+                longitude,
+            },
+            accuracy: position.accuracy,
+        }
+    }
+}
+
+impl Axis {
+    fn new(position_variance: f64) -> Self {
+        Axis {
+            position: 0.0,
+            speed: 0.0,
+            covariance: [[position_variance, 0.0], [0.0, INITIAL_SPEED_VARIANCE]],
+        }
+    }
+
+    // Predicts `dt` seconds ahead at constant speed, then corrects towards
+    // the measured position.
+    fn step(&mut self, measured: f64, dt: f64, settings: &Smoothing) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let q = settings.process_noise.powi(2);
+
+        self.position += self.speed * dt;
+        let p00 = p00 + dt * (p10 + p01) + dt * dt * p11 + q * dt.powi(4) / 4.0;
+        let p01 = p01 + dt * p11 + q * dt.powi(3) / 2.0;
+        let p10 = p10 + dt * p11 + q * dt.powi(3) / 2.0;
+        let p11 = p11 + q * dt * dt;
+
+        let innovation = measured - self.position;
+        let innovation_variance = p00 + settings.measurement_noise.powi(2);
+        let (k0, k1) = (p00 / innovation_variance, p10 / innovation_variance);
+
+        self.position += k0 * innovation;
+        self.speed += k1 * innovation;
+        self.covariance = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+pub(super) fn smooth(positions: &[Position], settings: Smoothing) -> Vec<Position> {
+    let mut filter = KalmanFilter::new(settings);
+    positions
+        .iter()
+        .map(|position| filter.next(position))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn at(seconds: i64, latitude: f64, longitude: f64) -> Position {
+        Position {
+            datetime: Utc.timestamp(1405594957 + seconds, 0),
+            location: haversine::Location {
+                latitude,
+                longitude,
+            },
+            accuracy: None,
+        }
+    }
+
+    fn length_km(positions: &[Position]) -> f64 {
+        positions
+            .windows(2)
+            .map(|pair| haversine::distance_km(&pair[0].location, &pair[1].location))
+            .sum()
+    }
+
+    #[test]
+    fn it_keeps_the_first_fix() {
+        let first = at(0, 37.96, 23.72);
+        let smoothed = smooth(std::slice::from_ref(&first), Smoothing::default());
+
+        assert_eq!(first.datetime, smoothed[0].datetime);
+        assert_eq!(first.location.latitude, smoothed[0].location.latitude);
+        assert_eq!(first.location.longitude, smoothed[0].location.longitude);
+    }
+
+    #[test]
+    fn it_damps_jitter() {
+        // About 10 m of jitter around a point, every 5 seconds.
+        let jittery: Vec<Position> = (0..120)
+            .map(|i| {
+                let jitter = (i as f64 * 2.4).sin() * 0.0001;
+                at(i * 5, 37.96 + jitter, 23.72 - jitter)
+            })
+            .collect();
+
+        let smoothed = smooth(&jittery, Smoothing::default());
+
+        assert!(length_km(&smoothed) < length_km(&jittery) / 2.0);
+    }
+
+    #[test]
+    fn it_follows_a_steady_drive() {
+        // About 40 km/h to the north east, for 5 minutes.
+        let drive: Vec<Position> = (0..60)
+            .map(|i| at(i * 5, 37.96 + i as f64 * 0.0004, 23.72 + i as f64 * 0.0004))
+            .collect();
+
+        let smoothed = smooth(&drive, Smoothing::default());
+        let (raw, smooth) = (length_km(&drive), length_km(&smoothed));
+
+        assert!((raw - smooth).abs() / raw < 0.02, "{} {}", raw, smooth);
+        let (last, smoothed_last) = (&drive[59].location, &smoothed[59].location);
+        assert!(haversine::distance_km(last, smoothed_last) < 0.01);
+    }
+}
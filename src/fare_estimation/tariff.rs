@@ -0,0 +1,849 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveTime, Offset, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::filters::Filter;
+use super::geodesy::Distance;
+use super::haversine::Location;
+use super::money::{Currency, Price, Rounding, RoundingPoint};
+use super::smoothing::Smoothing;
+use super::{
+    ReadError, FARE_PER_HOUR_IDLE, FARE_PER_KM_DAY, FARE_PER_KM_NIGHT, IDLE_SPEED, MAX_SPEED,
+    MINIMUM_FARE, STANDARD_FLAG,
+};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Speeds are in km/h, distance rates per km and the idle rate per hour.
+// Rates and fees are exact decimals in `currency`.
+// Day and night are decided in the tariff's IANA time zone, UTC by default.
+// The night rate applies from just after `night_start` up to and including
+// `night_end`, and may wrap around midnight (e.g. 22:00 to 06:00).
+// Fares are rounded with `rounding`, either per segment or on the total.
+// Positions at exactly 0,0 are rejected unless `reject_null_island` is off,
+// and so are positions outside `bounds`, if given. With `smoothing`,
+// positions are smoothed before being split into segments. Segments are
+// then priced unless one of `filters` discards them, with their length
+// measured by `distance`, and close positions on a flat Earth if
+// `flat_earth` is on.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tariff {
+    #[serde(deserialize_with = "deserialize_timezone")]
+    pub timezone: Tz,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub night_start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub night_end: NaiveTime,
+    pub max_speed: f64,
+    pub idle_speed: f64,
+    pub fare_per_hour_idle: Price,
+    pub fare_per_km_day: Price,
+    pub fare_per_km_night: Price,
+    pub standard_flag: Price,
+    pub minimum_fare: Price,
+    pub currency: Currency,
+    pub rounding: Rounding,
+    pub rounding_point: RoundingPoint,
+    pub reject_null_island: bool,
+    pub bounds: Option<BoundingBox>,
+    pub smoothing: Option<Smoothing>,
+    pub filters: Vec<Filter>,
+    pub distance: Distance,
+    pub flat_earth: bool,
+}
+
+// The area a tariff's rides take place in, in degrees. It can't cross the
+// antimeridian.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BoundingBox {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, location: &Location) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&location.latitude)
+            && (self.min_longitude..=self.max_longitude).contains(&location.longitude)
+    }
+}
+
+impl Default for Tariff {
+    fn default() -> Self {
+        Tariff {
+            timezone: Tz::UTC,
+            night_start: NaiveTime::from_hms(0, 0, 0),
+            night_end: NaiveTime::from_hms(5, 0, 0),
+            max_speed: MAX_SPEED,
+            idle_speed: IDLE_SPEED,
+            fare_per_hour_idle: FARE_PER_HOUR_IDLE,
+            fare_per_km_day: FARE_PER_KM_DAY,
+            fare_per_km_night: FARE_PER_KM_NIGHT,
+            standard_flag: STANDARD_FLAG,
+            minimum_fare: MINIMUM_FARE,
+            currency: Currency::EUR,
+            rounding: Rounding::HalfUp,
+            rounding_point: RoundingPoint::Total,
+            reject_null_island: true,
+            bounds: None,
+            smoothing: None,
+            filters: vec![Filter::MaxSpeed],
+            distance: Distance::Haversine,
+            flat_earth: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TariffError {
+    IOError(io::Error),
+    TOMLError(toml::de::Error),
+    JSONError(serde_json::Error),
+    UnknownFormatError { path: String },
+    InvalidValueError { field: String, reason: String },
+}
+
+impl From<io::Error> for TariffError {
+    fn from(error: io::Error) -> Self {
+        TariffError::IOError(error)
+    }
+}
+
+impl From<toml::de::Error> for TariffError {
+    fn from(error: toml::de::Error) -> Self {
+        TariffError::TOMLError(error)
+    }
+}
+
+impl From<serde_json::Error> for TariffError {
+    fn from(error: serde_json::Error) -> Self {
+        TariffError::JSONError(error)
+    }
+}
+
+impl Tariff {
+    // The format is picked from the file extension: `.toml` or `.json`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TariffError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml_str(&content),
+            Some("json") => Self::from_json_str(&content),
+            _ => Err(TariffError::UnknownFormatError {
+                path: path.display().to_string(),
+            }),
+        }
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self, TariffError> {
+        let tariff: Tariff = toml::from_str(content)?;
+        tariff.validate()?;
+        Ok(tariff)
+    }
+
+    pub fn from_json_str(content: &str) -> Result<Self, TariffError> {
+        let tariff: Tariff = serde_json::from_str(content)?;
+        tariff.validate()?;
+        Ok(tariff)
+    }
+
+    pub fn validate(&self) -> Result<(), TariffError> {
+        for (field, value) in [
+            ("max_speed", self.max_speed),
+            ("idle_speed", self.idle_speed),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(invalid(field, "must be a finite, non-negative number"));
+            }
+        }
+        for (field, price) in [
+            ("fare_per_hour_idle", self.fare_per_hour_idle),
+            ("fare_per_km_day", self.fare_per_km_day),
+            ("fare_per_km_night", self.fare_per_km_night),
+            ("standard_flag", self.standard_flag),
+            ("minimum_fare", self.minimum_fare),
+        ] {
+            if price.micros() < 0 {
+                return Err(invalid(field, "must not be negative"));
+            }
+        }
+        if self.rounding == Rounding::Cash && self.currency.minor_digits() < 2 {
+            return Err(invalid(
+                "rounding",
+                "cash rounding needs a currency with at least two decimal places",
+            ));
+        }
+
+        if self.max_speed == 0.0 {
+            return Err(invalid("max_speed", "must be greater than zero"));
+        }
+        if self.idle_speed >= self.max_speed {
+            return Err(invalid("idle_speed", "must be lower than max_speed"));
+        }
+        if self.night_start == self.night_end {
+            return Err(invalid("night_end", "must be different from night_start"));
+        }
+        if let Some(bounds) = &self.bounds {
+            for (field, value, limit) in [
+                ("bounds.min_latitude", bounds.min_latitude, 90.0),
+                ("bounds.max_latitude", bounds.max_latitude, 90.0),
+                ("bounds.min_longitude", bounds.min_longitude, 180.0),
+                ("bounds.max_longitude", bounds.max_longitude, 180.0),
+            ] {
+                if !(-limit..=limit).contains(&value) {
+                    return Err(invalid(field, "must be a valid coordinate"));
+                }
+            }
+            if bounds.min_latitude > bounds.max_latitude {
+                return Err(invalid(
+                    "bounds.max_latitude",
+                    "must not be below min_latitude",
+                ));
+            }
+            if bounds.min_longitude > bounds.max_longitude {
+                return Err(invalid(
+                    "bounds.max_longitude",
+                    "must not be below min_longitude",
+                ));
+            }
+        }
+        for filter in &self.filters {
+            let (field, value) = match *filter {
+                Filter::MaxSpeed => continue,
+                Filter::MaxAcceleration { max_acceleration } => {
+                    ("filters.max_acceleration", max_acceleration)
+                }
+                Filter::MinDisplacement { min_distance } => ("filters.min_distance", min_distance),
+                Filter::Accuracy { max_accuracy } => ("filters.max_accuracy", max_accuracy),
+                Filter::Spike { max_turn, min_leg } => {
+                    if !(0.0..=180.0).contains(&max_turn) {
+                        return Err(invalid("filters.max_turn", "must be between 0 and 180"));
+                    }
+                    ("filters.min_leg", min_leg)
+                }
+            };
+            if !value.is_finite() || value < 0.0 {
+                return Err(invalid(field, "must be a finite, non-negative number"));
+            }
+        }
+        if let Some(smoothing) = &self.smoothing {
+            for (field, value) in [
+                ("smoothing.process_noise", smoothing.process_noise),
+                ("smoothing.measurement_noise", smoothing.measurement_noise),
+            ] {
+                if !value.is_finite() || value <= 0.0 {
+                    return Err(invalid(field, "must be a finite, positive number"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether positions at `location` are accepted for this tariff's rides.
+    pub(super) fn check_location(&self, location: &Location) -> Result<(), ReadError> {
+        if self.reject_null_island && location.latitude == 0.0 && location.longitude == 0.0 {
+            return Err(ReadError::NullIslandError);
+        }
+        match &self.bounds {
+            Some(bounds) if !bounds.contains(location) => Err(ReadError::OutOfBoundsError {
+                latitude: location.latitude,
+                longitude: location.longitude,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_day(&self, datetime: &DateTime<Utc>) -> bool {
+        let seconds = datetime
+            .with_timezone(&self.timezone)
+            .num_seconds_from_midnight();
+        let start = self.night_start.num_seconds_from_midnight();
+        let end = self.night_end.num_seconds_from_midnight();
+
+        let is_night = if start < end {
+            seconds > start && seconds <= end
+        } else {
+            seconds > start || seconds <= end
+        };
+
+        !is_night
+    }
+
+    // Instants strictly between `start` and `end` where `is_day` may change:
+    // one second after each end of the night window, and every UTC offset
+    // change, which covers window boundaries skipped by a DST gap.
+    pub fn rate_boundaries(
+        &self,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        if *end - *start < self.shortest_band() && self.is_day(start) == self.is_day(end) {
+            return vec![];
+        }
+
+        let mut boundaries = vec![];
+        let mut day = start
+            .with_timezone(&self.timezone)
+            .date()
+            .naive_local()
+            .pred();
+        let last_day = end
+            .with_timezone(&self.timezone)
+            .date()
+            .naive_local()
+            .succ();
+
+        while day <= last_day {
+            for time in [self.night_start, self.night_end] {
+                let local = day.and_time(time) + Duration::seconds(1);
+                match self.timezone.from_local_datetime(&local) {
+                    LocalResult::Single(dt) => boundaries.push(dt.with_timezone(&Utc)),
+                    LocalResult::Ambiguous(earliest, latest) => {
+                        boundaries.push(earliest.with_timezone(&Utc));
+                        boundaries.push(latest.with_timezone(&Utc));
+                    }
+                    LocalResult::None => {}
+                }
+            }
+            day = day.succ();
+        }
+        offset_changes(self.timezone, *start, *end, &mut boundaries);
+
+        boundaries.retain(|boundary| boundary > start && boundary < end);
+        boundaries.sort();
+        boundaries.dedup();
+        boundaries
+    }
+
+    // A segment shorter than this cannot go through a whole day or night band,
+    // even on a DST night, so it changes rate at most once.
+    fn shortest_band(&self) -> Duration {
+        let night = (self.night_end.num_seconds_from_midnight() as i64
+            - self.night_start.num_seconds_from_midnight() as i64)
+            .rem_euclid(SECONDS_PER_DAY);
+        let day = SECONDS_PER_DAY - night;
+
+        Duration::seconds(night.min(day)) - Duration::hours(2)
+    }
+}
+
+// Assumes time zones don't change their UTC offset twice within a week.
+fn offset_changes(
+    tz: Tz,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    changes: &mut Vec<DateTime<Utc>>,
+) {
+    let offset = |datetime: DateTime<Utc>| datetime.with_timezone(&tz).offset().fix();
+
+    if to - from > Duration::weeks(1) {
+        let middle = from + (to - from) / 2;
+        offset_changes(tz, from, middle, changes);
+        offset_changes(tz, middle, to, changes);
+        return;
+    }
+
+    let target = offset(to);
+    if offset(from) == target {
+        return;
+    }
+
+    let (mut before, mut after) = (from.timestamp(), to.timestamp());
+    while after - before > 1 {
+        let middle = before + (after - before) / 2;
+        if offset(Utc.timestamp(middle, 0)) == target {
+            after = middle;
+        } else {
+            before = middle;
+        }
+    }
+    changes.push(Utc.timestamp(after, 0));
+}
+
+// Accepts `HH:MM` or `HH:MM:SS`.
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M"))
+        .map_err(de::Error::custom)
+}
+
+fn deserialize_timezone<'de, D>(deserializer: D) -> Result<Tz, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    name.parse().map_err(de::Error::custom)
+}
+
+fn invalid(field: &str, reason: &str) -> TariffError {
+    TariffError::InvalidValueError {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[test]
+fn default_tariff_is_valid() {
+    assert!(Tariff::default().validate().is_ok());
+}
+
+#[test]
+fn tariff_from_toml() {
+    let tariff = Tariff::from_toml_str(
+        r#"
+        fare_per_km_day = 0.80
+        minimum_fare = 4.0
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(Price::from_micros(800_000), tariff.fare_per_km_day);
+    assert_eq!(Price::from_micros(4_000_000), tariff.minimum_fare);
+    assert_eq!(FARE_PER_KM_NIGHT, tariff.fare_per_km_night);
+}
+
+#[test]
+fn tariff_from_json() {
+    let tariff = Tariff::from_json_str(r#"{"max_speed": 120.0, "standard_flag": 2.5}"#).unwrap();
+
+    assert_eq!(120.0, tariff.max_speed);
+    assert_eq!(Price::from_micros(2_500_000), tariff.standard_flag);
+    assert_eq!(IDLE_SPEED, tariff.idle_speed);
+}
+
+#[test]
+fn tariff_timezone() {
+    let tariff = Tariff::from_toml_str(r#"timezone = "Europe/Athens""#).unwrap();
+    assert_eq!(chrono_tz::Europe::Athens, tariff.timezone);
+
+    assert!(matches!(
+        Tariff::from_json_str(r#"{"timezone": "Europe/Atlantis"}"#),
+        Err(TariffError::JSONError(_))
+    ));
+}
+
+#[test]
+fn tariff_from_file() {
+    assert_eq!(
+        Tariff::default(),
+        Tariff::from_file("tariffs/default.toml").unwrap()
+    );
+    let athens = Tariff::from_file("tariffs/athens.toml").unwrap();
+    assert_eq!(chrono_tz::Europe::Athens, athens.timezone);
+    assert!(athens.bounds.is_some());
+    assert!(matches!(
+        Tariff::from_file("paths.csv"),
+        Err(TariffError::UnknownFormatError { .. })
+    ));
+}
+
+#[test]
+fn tariff_night_window() {
+    let tariff = Tariff::from_toml_str(
+        r#"
+        night_start = "22:00"
+        night_end = "06:30:00"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(NaiveTime::from_hms(22, 0, 0), tariff.night_start);
+    assert_eq!(NaiveTime::from_hms(6, 30, 0), tariff.night_end);
+
+    assert!(matches!(
+        Tariff::from_toml_str(r#"night_start = "25:00""#),
+        Err(TariffError::TOMLError(_))
+    ));
+}
+
+#[test]
+fn tariff_rounding() {
+    let tariff = Tariff::from_toml_str(
+        r#"
+        currency = "CHF"
+        rounding = "cash"
+        rounding_point = "segment"
+        "#,
+    )
+    .unwrap();
+    assert_eq!("CHF", tariff.currency.code());
+    assert_eq!(Rounding::Cash, tariff.rounding);
+    assert_eq!(RoundingPoint::Segment, tariff.rounding_point);
+
+    for content in [r#"currency = "euro""#, r#"rounding = "up""#] {
+        assert!(matches!(
+            Tariff::from_toml_str(content),
+            Err(TariffError::TOMLError(_))
+        ));
+    }
+}
+
+#[test]
+fn tariff_bounds() {
+    let tariff = Tariff::from_toml_str(
+        r#"
+        reject_null_island = false
+
+        [bounds]
+        min_latitude = 37.5
+        max_latitude = 38.5
+        min_longitude = 23.0
+        max_longitude = 24.5
+        "#,
+    )
+    .unwrap();
+    let at = |latitude, longitude| Location {
+        latitude,
+        longitude,
+    };
+
+    assert!(tariff.check_location(&at(37.96, 23.72)).is_ok());
+    assert!(tariff.check_location(&at(37.5, 24.5)).is_ok());
+    assert!(matches!(
+        tariff.check_location(&at(23.72, 37.96)),
+        Err(ReadError::OutOfBoundsError { .. })
+    ));
+    assert!(matches!(
+        tariff.check_location(&at(0.0, 0.0)),
+        Err(ReadError::OutOfBoundsError { .. })
+    ));
+
+    assert!(matches!(
+        Tariff::default().check_location(&at(0.0, 0.0)),
+        Err(ReadError::NullIslandError)
+    ));
+    assert!(Tariff::default().check_location(&at(0.0, 23.72)).is_ok());
+    assert!(Tariff {
+        reject_null_island: false,
+        ..Tariff::default()
+    }
+    .check_location(&at(0.0, 0.0))
+    .is_ok());
+}
+
+#[test]
+fn tariff_smoothing() {
+    assert_eq!(None, Tariff::default().smoothing);
+    assert_eq!(
+        Some(Smoothing::default()),
+        Tariff::from_toml_str("[smoothing]").unwrap().smoothing
+    );
+    assert_eq!(
+        Some(Smoothing {
+            process_noise: 1.5,
+            measurement_noise: 5.0,
+        }),
+        Tariff::from_json_str(r#"{"smoothing": {"process_noise": 1.5, "measurement_noise": 5.0}}"#)
+            .unwrap()
+            .smoothing
+    );
+}
+
+#[test]
+fn tariff_distance() {
+    assert_eq!(Distance::Haversine, Tariff::default().distance);
+    assert_eq!(
+        Distance::Wgs84,
+        Tariff::from_toml_str(r#"distance = "wgs84""#)
+            .unwrap()
+            .distance
+    );
+    assert!(matches!(
+        Tariff::from_toml_str(r#"distance = "flat""#),
+        Err(TariffError::TOMLError(_))
+    ));
+    assert!(!Tariff::default().flat_earth);
+    assert!(
+        Tariff::from_toml_str("flat_earth = true")
+            .unwrap()
+            .flat_earth
+    );
+}
+
+#[test]
+fn tariff_filters() {
+    assert_eq!(vec![Filter::MaxSpeed], Tariff::default().filters);
+    let tariff = Tariff::from_toml_str(
+        "[[filters]]\nkind = \"max_speed\"\n\
+         [[filters]]\nkind = \"spike\"\nmax_turn = 150.0\nmin_leg = 50.0",
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            Filter::MaxSpeed,
+            Filter::Spike {
+                max_turn: 150.0,
+                min_leg: 50.0
+            }
+        ],
+        tariff.filters
+    );
+    assert_eq!(
+        Vec::<Filter>::new(),
+        Tariff::from_json_str(r#"{"filters": []}"#).unwrap().filters
+    );
+    for content in [
+        "[[filters]]\nkind = \"teleport\"",
+        "[[filters]]\nkind = \"accuracy\"",
+        "[[filters]]\nkind = \"accuracy\"\nmax_accuracy = 20.0\nmin_leg = 5.0",
+    ] {
+        assert!(matches!(
+            Tariff::from_toml_str(content),
+            Err(TariffError::TOMLError(_))
+        ));
+    }
+}
+
+#[test]
+fn tariff_rejects_unknown_fields() {
+    assert!(matches!(
+        Tariff::from_toml_str("fare_per_kilometre = 1.0"),
+        Err(TariffError::TOMLError(_))
+    ));
+}
+
+#[test]
+fn tariff_rejects_inexact_prices() {
+    for content in [
+        "minimum_fare = nan",
+        "minimum_fare = 3.4700001",
+        "fare_per_km_day = \"0,74\"",
+    ] {
+        assert!(
+            matches!(
+                Tariff::from_toml_str(content),
+                Err(TariffError::TOMLError(_))
+            ),
+            "{}",
+            content
+        );
+    }
+    let tariff = Tariff::from_toml_str("fare_per_km_day = \"0.745\"").unwrap();
+    assert_eq!(Price::from_micros(745_000), tariff.fare_per_km_day);
+}
+
+#[test]
+fn tariff_rejects_invalid_values() {
+    for (content, want_field) in [
+        ("fare_per_km_day = -0.1", "fare_per_km_day"),
+        ("max_speed = 0.0", "max_speed"),
+        ("idle_speed = 100.0", "idle_speed"),
+        ("idle_speed = 20.0\nmax_speed = 15.0", "idle_speed"),
+        ("rounding = \"cash\"\ncurrency = \"JPY\"", "rounding"),
+        ("night_start = \"05:00\"", "night_end"),
+        (
+            "[smoothing]\nmeasurement_noise = 0.0",
+            "smoothing.measurement_noise",
+        ),
+        (
+            "[bounds]\nmin_latitude = 38.5\nmax_latitude = 37.5\n\
+             min_longitude = 23.0\nmax_longitude = 24.5",
+            "bounds.max_latitude",
+        ),
+        (
+            "[bounds]\nmin_latitude = 37.5\nmax_latitude = 38.5\n\
+             min_longitude = 23.0\nmax_longitude = 240.5",
+            "bounds.max_longitude",
+        ),
+        (
+            "[[filters]]\nkind = \"spike\"\nmax_turn = 200.0\nmin_leg = 50.0",
+            "filters.max_turn",
+        ),
+        (
+            "[[filters]]\nkind = \"min_displacement\"\nmin_distance = -1.0",
+            "filters.min_distance",
+        ),
+    ] {
+        match Tariff::from_toml_str(content) {
+            Err(TariffError::InvalidValueError { field, .. }) => assert_eq!(want_field, field),
+            other => panic!("{}: expected an invalid value, got {:?}", content, other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod day_night_tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn athens() -> Tariff {
+        Tariff {
+            timezone: chrono_tz::Europe::Athens,
+            ..Tariff::default()
+        }
+    }
+
+    #[test]
+    fn it_uses_local_time() {
+        let tariff = athens();
+
+        // 02:30 UTC is 04:30 in Athens during winter, 05:30 during summer.
+        assert!(!tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(2, 30, 0)));
+        assert!(tariff.is_day(&Utc.ymd(2021, 7, 15).and_hms(2, 30, 0)));
+
+        // 22:30 UTC is already past midnight in Athens.
+        assert!(!tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(22, 30, 0)));
+        assert!(Tariff::default().is_day(&Utc.ymd(2021, 1, 15).and_hms(22, 30, 0)));
+    }
+
+    #[test]
+    fn it_handles_spring_forward() {
+        // On 2021-03-28, Athens jumps from 03:00 EET to 04:00 EEST at 01:00 UTC.
+        let tariff = athens();
+
+        assert!(tariff.is_day(&Utc.ymd(2021, 3, 27).and_hms(22, 0, 0))); // 00:00 EET
+        assert!(!tariff.is_day(&Utc.ymd(2021, 3, 27).and_hms(22, 0, 1))); // 00:00:01 EET
+        assert!(!tariff.is_day(&Utc.ymd(2021, 3, 28).and_hms(0, 59, 59))); // 02:59:59 EET
+        assert!(!tariff.is_day(&Utc.ymd(2021, 3, 28).and_hms(1, 0, 0))); // 04:00 EEST
+        assert!(!tariff.is_day(&Utc.ymd(2021, 3, 28).and_hms(2, 0, 0))); // 05:00 EEST
+        assert!(tariff.is_day(&Utc.ymd(2021, 3, 28).and_hms(2, 0, 1))); // 05:00:01 EEST
+    }
+
+    #[test]
+    fn it_wraps_around_midnight() {
+        let tariff = Tariff {
+            night_start: NaiveTime::from_hms(22, 0, 0),
+            night_end: NaiveTime::from_hms(6, 0, 0),
+            ..Tariff::default()
+        };
+
+        assert!(tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(22, 0, 0)));
+        assert!(!tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(22, 0, 1)));
+        assert!(!tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(0, 0, 0)));
+        assert!(!tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(6, 0, 0)));
+        assert!(tariff.is_day(&Utc.ymd(2021, 1, 15).and_hms(6, 0, 1)));
+    }
+
+    #[test]
+    fn it_handles_fall_back() {
+        // On 2021-10-31, Athens goes back from 04:00 EEST to 03:00 EET at 01:00 UTC.
+        let tariff = athens();
+
+        assert!(tariff.is_day(&Utc.ymd(2021, 10, 30).and_hms(21, 0, 0))); // 00:00 EEST
+        assert!(!tariff.is_day(&Utc.ymd(2021, 10, 30).and_hms(21, 0, 1))); // 00:00:01 EEST
+        assert!(!tariff.is_day(&Utc.ymd(2021, 10, 31).and_hms(0, 30, 0))); // 03:30 EEST
+        assert!(!tariff.is_day(&Utc.ymd(2021, 10, 31).and_hms(1, 30, 0))); // 03:30 EET
+        assert!(!tariff.is_day(&Utc.ymd(2021, 10, 31).and_hms(2, 0, 1))); // 04:00:01 EET
+        assert!(!tariff.is_day(&Utc.ymd(2021, 10, 31).and_hms(3, 0, 0))); // 05:00 EET
+        assert!(tariff.is_day(&Utc.ymd(2021, 10, 31).and_hms(3, 0, 1))); // 05:00:01 EET
+    }
+}
+
+#[cfg(test)]
+mod rate_boundaries_tests {
+    use super::*;
+
+    fn boundaries(tariff: &Tariff, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        tariff.rate_boundaries(&start, &end)
+    }
+
+    #[test]
+    fn it_has_no_boundary_within_a_band() {
+        let tariff = Tariff::default();
+
+        assert!(boundaries(
+            &tariff,
+            Utc.ymd(2021, 1, 15).and_hms(10, 0, 0),
+            Utc.ymd(2021, 1, 15).and_hms(10, 20, 0),
+        )
+        .is_empty());
+        assert!(boundaries(
+            &tariff,
+            Utc.ymd(2021, 1, 15).and_hms(1, 0, 0),
+            Utc.ymd(2021, 1, 15).and_hms(5, 0, 0),
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn it_finds_the_start_and_end_of_the_night() {
+        let tariff = Tariff::default();
+
+        assert_eq!(
+            vec![Utc.ymd(2021, 1, 15).and_hms(5, 0, 1)],
+            boundaries(
+                &tariff,
+                Utc.ymd(2021, 1, 15).and_hms(4, 59, 59),
+                Utc.ymd(2021, 1, 15).and_hms(5, 20, 0),
+            )
+        );
+        assert_eq!(
+            vec![
+                Utc.ymd(2021, 1, 16).and_hms(0, 0, 1),
+                Utc.ymd(2021, 1, 16).and_hms(5, 0, 1),
+            ],
+            boundaries(
+                &tariff,
+                Utc.ymd(2021, 1, 15).and_hms(22, 0, 0),
+                Utc.ymd(2021, 1, 16).and_hms(7, 0, 0),
+            )
+        );
+    }
+
+    #[test]
+    fn it_finds_boundaries_on_dst_nights() {
+        let tariff = Tariff {
+            timezone: chrono_tz::Europe::Athens,
+            ..Tariff::default()
+        };
+
+        // Spring forward: the night is one hour shorter.
+        assert_eq!(
+            vec![
+                Utc.ymd(2021, 3, 27).and_hms(22, 0, 1),
+                Utc.ymd(2021, 3, 28).and_hms(1, 0, 0),
+                Utc.ymd(2021, 3, 28).and_hms(2, 0, 1),
+            ],
+            boundaries(
+                &tariff,
+                Utc.ymd(2021, 3, 27).and_hms(21, 0, 0),
+                Utc.ymd(2021, 3, 28).and_hms(3, 0, 0),
+            )
+        );
+
+        // Fall back: the night is one hour longer.
+        assert_eq!(
+            vec![
+                Utc.ymd(2021, 10, 30).and_hms(21, 0, 1),
+                Utc.ymd(2021, 10, 31).and_hms(1, 0, 0),
+                Utc.ymd(2021, 10, 31).and_hms(3, 0, 1),
+            ],
+            boundaries(
+                &tariff,
+                Utc.ymd(2021, 10, 30).and_hms(20, 0, 0),
+                Utc.ymd(2021, 10, 31).and_hms(4, 0, 0),
+            )
+        );
+    }
+
+    #[test]
+    fn it_finds_boundaries_skipped_by_a_dst_gap() {
+        // 03:30:01 doesn't exist in Athens on 2021-03-28: clocks jump from
+        // 03:00 to 04:00, so the day starts at the jump.
+        let tariff = Tariff {
+            timezone: chrono_tz::Europe::Athens,
+            night_end: NaiveTime::from_hms(3, 30, 0),
+            ..Tariff::default()
+        };
+
+        let got = boundaries(
+            &tariff,
+            Utc.ymd(2021, 3, 28).and_hms(0, 30, 0),
+            Utc.ymd(2021, 3, 28).and_hms(1, 30, 0),
+        );
+        assert_eq!(vec![Utc.ymd(2021, 3, 28).and_hms(1, 0, 0)], got);
+        assert!(!tariff.is_day(&Utc.ymd(2021, 3, 28).and_hms(0, 59, 59)));
+        assert!(tariff.is_day(&got[0]));
+    }
+}
@@ -0,0 +1,161 @@
+use chrono::prelude::*;
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+// Whole numbers this far from the epoch are taken as milliseconds, then
+// microseconds, when detecting the unit: 1e11 seconds is in the year 5138.
+const MILLISECONDS_FROM: i64 = 100_000_000_000;
+const MICROSECONDS_FROM: i64 = 100_000_000_000_000;
+
+// How timestamps are written in the input.
+//
+// `Seconds` may have a decimal fraction, e.g. `1405594957.25`; `Milliseconds`
+// and `Microseconds` are whole numbers. `Rfc3339` is e.g.
+// `2014-07-17T11:02:37.25+03:00`. `Auto` takes decimal numbers as seconds,
+// whole numbers as seconds, milliseconds or microseconds depending on their
+// size, and anything else as RFC 3339.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestampFormat {
+    #[default]
+    Auto,
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Rfc3339,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "auto" => Ok(TimestampFormat::Auto),
+            "seconds" => Ok(TimestampFormat::Seconds),
+            "milliseconds" => Ok(TimestampFormat::Milliseconds),
+            "microseconds" => Ok(TimestampFormat::Microseconds),
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            _ => Err(format!("unknown timestamp format {:?}", name)),
+        }
+    }
+}
+
+impl TimestampFormat {
+    // `None` if `text` isn't a timestamp in this format, or is out of range.
+    pub fn parse(self, text: &str) -> Option<DateTime<Utc>> {
+        let text = text.trim();
+
+        match self {
+            TimestampFormat::Auto => match text.parse::<i64>() {
+                Ok(whole) if whole.abs() >= MICROSECONDS_FROM => from_units(whole, 1_000_000),
+                Ok(whole) if whole.abs() >= MILLISECONDS_FROM => from_units(whole, 1_000),
+                Ok(whole) => from_units(whole, 1),
+                Err(_) => TimestampFormat::Seconds
+                    .parse(text)
+                    .or_else(|| TimestampFormat::Rfc3339.parse(text)),
+            },
+            TimestampFormat::Seconds => {
+                let (seconds, nanos) = parse_decimal_seconds(text)?;
+                Utc.timestamp_opt(seconds, nanos).single()
+            }
+            TimestampFormat::Milliseconds => from_units(text.parse().ok()?, 1_000),
+            TimestampFormat::Microseconds => from_units(text.parse().ok()?, 1_000_000),
+            TimestampFormat::Rfc3339 => DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|datetime| datetime.with_timezone(&Utc)),
+        }
+    }
+}
+
+fn from_units(value: i64, per_second: i64) -> Option<DateTime<Utc>> {
+    let nanos = value.rem_euclid(per_second) * (NANOS_PER_SECOND / per_second);
+    Utc.timestamp_opt(value.div_euclid(per_second), nanos as u32)
+        .single()
+}
+
+// Splits e.g. `-1.25` into whole seconds and nanoseconds, `(-2, 750_000_000)`,
+// without going through a float.
+fn parse_decimal_seconds(text: &str) -> Option<(i64, u32)> {
+    let (whole, fraction) = match text.split_once('.') {
+        Some((_, "")) => return None,
+        Some(parts) => parts,
+        None => (text, ""),
+    };
+    if fraction.len() > 9 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let seconds: i64 = match whole {
+        "" | "-" | "+" if !fraction.is_empty() => 0,
+        _ => whole.parse().ok()?,
+    };
+    let nanos: i64 = format!("{:0<9}", fraction).parse().ok()?;
+
+    if whole.starts_with('-') && nanos > 0 {
+        Some((seconds - 1, (NANOS_PER_SECOND - nanos) as u32))
+    } else {
+        Some((seconds, nanos as u32))
+    }
+}
+
+#[test]
+fn it_parses_each_format() {
+    let at = |seconds: i64, millis: i64| Some(Utc.timestamp_millis(seconds * 1000 + millis));
+
+    for (format, text, want) in [
+        (TimestampFormat::Seconds, "1405594957", at(1405594957, 0)),
+        (
+            TimestampFormat::Seconds,
+            "1405594957.25",
+            at(1405594957, 250),
+        ),
+        (TimestampFormat::Seconds, "-1.25", at(-2, 750)),
+        (
+            TimestampFormat::Milliseconds,
+            "1405594957250",
+            at(1405594957, 250),
+        ),
+        (
+            TimestampFormat::Microseconds,
+            "1405594957250000",
+            at(1405594957, 250),
+        ),
+        (
+            TimestampFormat::Rfc3339,
+            "2014-07-17T14:02:37.25+03:00",
+            at(1405594957, 250),
+        ),
+        (
+            TimestampFormat::Rfc3339,
+            "2014-07-17T11:02:37Z",
+            at(1405594957, 0),
+        ),
+        (TimestampFormat::Seconds, "soon", None),
+        (TimestampFormat::Seconds, "1405594957.", None),
+        (TimestampFormat::Milliseconds, "1405594957.25", None),
+        (TimestampFormat::Rfc3339, "1405594957", None),
+        (TimestampFormat::Seconds, "99999999999999999", None),
+    ] {
+        assert_eq!(want, format.parse(text), "{:?} {}", format, text);
+    }
+}
+
+#[test]
+fn it_detects_the_format() {
+    let want = Some(Utc.timestamp_millis(1405594957250));
+
+    for text in [
+        "1405594957.25",
+        "1405594957250",
+        "1405594957250000",
+        " 2014-07-17T11:02:37.250Z ",
+    ] {
+        assert_eq!(want, TimestampFormat::Auto.parse(text), "{}", text);
+    }
+    assert_eq!(
+        Some(Utc.timestamp(1405594957, 0)),
+        TimestampFormat::Auto.parse("1405594957")
+    );
+    assert_eq!(None, TimestampFormat::Auto.parse("yesterday"));
+}
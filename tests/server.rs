@@ -0,0 +1,204 @@
+use fare_estimation::fare_estimation::{
+    estimate_fare, serve, AmountFormat, Options, Price, ServerOptions, Tariff,
+};
+use hyper::body::to_bytes;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use serde_json::Value;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+// Starts a server on a free port on localhost.
+fn start(options: ServerOptions) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, options));
+    address
+}
+
+async fn request(
+    address: SocketAddr,
+    method: Method,
+    path: &str,
+    body: impl Into<Body>,
+) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", address, path))
+        .body(body.into())
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+// Ride 1 of paths.csv, as JSON.
+fn first_ride() -> String {
+    let positions: Vec<String> = std::fs::read_to_string("paths.csv")
+        .unwrap()
+        .lines()
+        .map(|row| row.split(',').collect::<Vec<_>>())
+        .filter(|fields| fields[0] == "1")
+        .map(|fields| {
+            format!(
+                r#"{{"lat":{},"lng":{},"timestamp":{}}}"#,
+                fields[1], fields[2], fields[3]
+            )
+        })
+        .collect();
+    format!(r#"{{"id_ride":1,"positions":[{}]}}"#, positions.join(","))
+}
+
+// An in-memory output that can be inspected once `estimate_fare` owned it.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn it_prices_a_json_ride() {
+    let address = start(ServerOptions {
+        amount_format: AmountFormat::MinorUnits,
+        ..ServerOptions::default()
+    });
+
+    let (status, body) = request(address, Method::POST, "/fare", first_ride()).await;
+    assert_eq!(StatusCode::OK, status, "{}", body);
+    let fare: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(1, fare["id_ride"]);
+    assert_eq!(1134, fare["fare_estimate"]);
+    assert_eq!(130, fare["flag"]);
+    assert_eq!(false, fare["minimum_applied"]);
+}
+
+#[tokio::test]
+async fn it_rejects_invalid_rides() {
+    let address = start(ServerOptions::default());
+
+    let ride = r#"{"id_ride":1,"positions":[
+        {"lat":37.96,"lng":23.72,"timestamp":1405594957},
+        {"lat":0,"lng":0,"timestamp":1405594966}
+    ]}"#;
+    let (status, body) = request(address, Method::POST, "/fare", ride).await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    let rejection: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!("null_island", rejection["kind"]);
+
+    let (status, body) = request(address, Method::POST, "/fare", "{").await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+    let rejection: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!("json", rejection["kind"]);
+}
+
+#[tokio::test]
+async fn it_streams_csv_fares() {
+    let address = start(ServerOptions::default());
+
+    let paths = std::fs::read_to_string("paths.csv").unwrap();
+    let want = SharedBuffer::default();
+    estimate_fare(
+        io::Cursor::new(paths.clone()),
+        want.clone(),
+        Options::default(),
+    )
+    .await
+    .unwrap();
+
+    let (status, body) = request(address, Method::POST, "/fares", paths).await;
+    assert_eq!(StatusCode::OK, status);
+    // Fares in input order, as when estimating from a file.
+    assert!(body.starts_with("1,11.34\n2,13.10\n"), "{}", body);
+    assert_eq!(want.contents(), body);
+}
+
+#[tokio::test]
+async fn it_uses_the_configured_tariff() {
+    let address = start(ServerOptions {
+        tariff: Tariff {
+            minimum_fare: Price::from_micros(20_000_000),
+            ..Tariff::default()
+        },
+        ..ServerOptions::default()
+    });
+
+    let (status, body) = request(address, Method::POST, "/fare", first_ride()).await;
+    assert_eq!(StatusCode::OK, status);
+    let fare: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!("20.00", fare["fare_estimate"]);
+    assert_eq!(true, fare["minimum_applied"]);
+
+    let (_, body) = request(
+        address,
+        Method::POST,
+        "/fares",
+        "9,37.96,23.72,1405594957\n",
+    )
+    .await;
+    assert_eq!("9,20.00\n", body);
+}
+
+// Longer than the 4 MiB the server reads of a ride.
+const TOO_LONG: usize = 4 * 1024 * 1024 + 1;
+
+#[tokio::test]
+async fn it_refuses_rides_that_are_too_long() {
+    let address = start(ServerOptions::default());
+
+    // Read, but not a ride.
+    let (status, _) = request(address, Method::POST, "/fare", vec![b' '; TOO_LONG - 1]).await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+
+    // Known to be too long from its Content-Length.
+    let (status, _) = request(address, Method::POST, "/fare", vec![b' '; TOO_LONG]).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+
+    // Only found to be too long while reading it.
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for _ in 0..TOO_LONG / 1024 + 1 {
+            if sender.send_data(vec![b' '; 1024].into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    let (status, _) = request(address, Method::POST, "/fare", body).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+}
+
+#[tokio::test]
+async fn it_answers_unknown_requests() {
+    let address = start(ServerOptions::default());
+
+    let (status, _) = request(address, Method::GET, "/fare", Body::empty()).await;
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, status);
+    let (status, _) = request(address, Method::POST, "/rides", Body::empty()).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn it_refuses_invalid_tariffs() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = ServerOptions {
+        tariff: Tariff {
+            minimum_fare: Price::from_micros(-1_000_000),
+            ..Tariff::default()
+        },
+        ..ServerOptions::default()
+    };
+    assert!(serve(listener, options).await.is_err());
+}